@@ -0,0 +1,266 @@
+use std::{fs, io::ErrorKind, os::unix::fs::PermissionsExt, path::Path, rc::Rc, time::Duration};
+
+use anyhow::{bail, Context};
+use serde::Serialize;
+use serde_json::json;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+    net::{TcpListener, UnixListener},
+    sync::{mpsc, oneshot},
+    task::spawn_local,
+    time::timeout,
+};
+
+use crate::{
+    config::ApiConfig,
+    log::{logPrint, redact},
+};
+
+/// Requests with longer headers than this are rejected
+const MAX_HEADER_LEN: usize = 16 * 1024;
+
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What a client asked to know. Answered right away from what the run loop last published,
+/// so that a running job doesn't hold it up.
+#[derive(Debug)]
+pub enum Query {
+    Status,
+    Metrics,
+}
+
+/// What a client asked the supervisor to do. Handled by the run loop, alongside its timers.
+#[derive(Debug)]
+pub enum Command {
+    /// Back up the named target, or all of them
+    Backup(Option<String>),
+    /// Check the named target's repository, or all of them
+    Check(Option<String>),
+    Update,
+    Restart(String),
+}
+
+pub struct Request {
+    pub command: Command,
+    pub reply: oneshot::Sender<Response>,
+}
+
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl Response {
+    pub fn json(status: u16, body: impl Serialize) -> Self {
+        Self {
+            status,
+            content_type: "application/json",
+            body: serde_json::to_string(&body).unwrap_or_else(|_| "null".to_string()),
+        }
+    }
+
+    pub fn ok(body: impl Serialize) -> Self {
+        Self::json(200, body)
+    }
+
+    /// Errors can contain secrets, e.g. a repository URL with credentials, so they're redacted
+    /// like log lines.
+    pub fn error(status: u16, err: &anyhow::Error) -> Self {
+        Self::json(status, json!({ "error": redact(format!("{err:#}")) }))
+    }
+
+    /// A plain text response, in the format Prometheus scrapes.
+    pub fn text(body: String) -> Self {
+        Self {
+            status: 200,
+            content_type: "text/plain; version=0.0.4; charset=utf-8",
+            body,
+        }
+    }
+}
+
+enum Route {
+    Query(Query),
+    Command(Command),
+}
+
+/// Start listening for API requests. Queries are answered with `answer`, commands are passed
+/// on through the returned channel. There's no authentication, so only bind to a loopback
+/// address or a protected socket.
+pub async fn serve(
+    config: &ApiConfig,
+    answer: impl Fn(Query) -> Response + 'static,
+) -> anyhow::Result<mpsc::Receiver<Request>> {
+    let (sender, receiver) = mpsc::channel(8);
+    let answer: Rc<dyn Fn(Query) -> Response> = Rc::new(answer);
+
+    match (&config.listen, &config.socket) {
+        (Some(addr), None) => {
+            let listener = TcpListener::bind(addr)
+                .await
+                .with_context(|| format!("Listening on {addr}"))?;
+            logPrint!("supervisor", "API listening on http://{addr}");
+
+            spawn_local(async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    spawn_local(handle_connection(stream, sender.clone(), answer.clone()));
+                }
+            });
+        }
+        (None, Some(path)) => {
+            let listener = bind_socket(path)?;
+            logPrint!("supervisor", "API listening on {}", path.display());
+
+            spawn_local(async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    spawn_local(handle_connection(stream, sender.clone(), answer.clone()));
+                }
+            });
+        }
+        _ => bail!("The API needs exactly one of listen or socket"),
+    }
+
+    Ok(receiver)
+}
+
+/// Wait for the next API request, or forever if the API isn't enabled.
+pub async fn next_request(receiver: &mut Option<mpsc::Receiver<Request>>) -> Request {
+    if let Some(receiver) = receiver {
+        if let Some(request) = receiver.recv().await {
+            return request;
+        }
+    }
+
+    std::future::pending().await
+}
+
+fn bind_socket(path: &Path) -> anyhow::Result<UnixListener> {
+    // A socket left behind by an earlier run would make binding fail
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != ErrorKind::NotFound => {
+            return Err(err).with_context(|| format!("Removing old socket {}", path.display()))
+        }
+        _ => {}
+    }
+
+    let listener =
+        UnixListener::bind(path).with_context(|| format!("Listening on {}", path.display()))?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o660))
+        .with_context(|| format!("Restricting access to {}", path.display()))?;
+
+    Ok(listener)
+}
+
+async fn handle_connection(
+    stream: impl AsyncRead + AsyncWrite + Unpin,
+    sender: mpsc::Sender<Request>,
+    answer: Rc<dyn Fn(Query) -> Response>,
+) {
+    let mut stream = BufReader::new(stream);
+
+    let response = match timeout(READ_TIMEOUT, read_request(&mut stream)).await {
+        Ok(Ok((method, path))) => match route(&method, &path) {
+            Some(Route::Query(query)) => answer(query),
+            Some(Route::Command(command)) => dispatch(command, &sender).await,
+            None => Response::json(
+                404,
+                json!({ "error": format!("No route for {method} {path}") }),
+            ),
+        },
+        Ok(Err(err)) => Response::error(400, &err),
+        Err(_) => return,
+    };
+
+    let body = response.body;
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        reason(response.status),
+        response.content_type,
+        body.len()
+    );
+
+    let stream = stream.get_mut();
+    let _ = stream.write_all(head.as_bytes()).await;
+    let _ = stream.write_all(body.as_bytes()).await;
+    let _ = stream.shutdown().await;
+}
+
+async fn dispatch(command: Command, sender: &mpsc::Sender<Request>) -> Response {
+    let (reply, response) = oneshot::channel();
+    if sender.send(Request { command, reply }).await.is_err() {
+        return Response::error(503, &anyhow::anyhow!("Supervisor is shutting down"));
+    }
+
+    response
+        .await
+        .unwrap_or_else(|_| Response::error(503, &anyhow::anyhow!("Supervisor is shutting down")))
+}
+
+/// Read the request line and skip the headers. Requests never need a body.
+async fn read_request(
+    stream: &mut (impl AsyncBufReadExt + Unpin),
+) -> anyhow::Result<(String, String)> {
+    let mut request_line = String::new();
+    stream
+        .read_line(&mut request_line)
+        .await
+        .context("Reading request")?;
+
+    let mut parts = request_line.split_whitespace();
+    let (Some(method), Some(path)) = (parts.next(), parts.next()) else {
+        bail!("Malformed request line");
+    };
+
+    let mut header_len = request_line.len();
+    let mut line = String::new();
+    loop {
+        line.clear();
+        let read = stream
+            .read_line(&mut line)
+            .await
+            .context("Reading headers")?;
+        header_len += read;
+
+        if read == 0 || line == "\r\n" || line == "\n" {
+            break;
+        }
+        if header_len > MAX_HEADER_LEN {
+            bail!("Request headers too long");
+        }
+    }
+
+    // Query strings aren't used for anything
+    let path = path.split('?').next().unwrap_or_default();
+    Ok((method.to_string(), path.to_string()))
+}
+
+fn route(method: &str, path: &str) -> Option<Route> {
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+
+    let command = match (method, segments.as_slice()) {
+        ("GET", ["status"]) => return Some(Route::Query(Query::Status)),
+        ("GET", ["metrics"]) => return Some(Route::Query(Query::Metrics)),
+        ("POST", ["backup"]) => Command::Backup(None),
+        ("POST", ["backup", target]) => Command::Backup(Some(target.to_string())),
+        ("POST", ["check"]) => Command::Check(None),
+        ("POST", ["check", target]) => Command::Check(Some(target.to_string())),
+        ("POST", ["update"]) => Command::Update,
+        ("POST", ["apps", app, "restart"]) => Command::Restart(app.to_string()),
+        _ => return None,
+    };
+
+    Some(Route::Command(command))
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        _ => "",
+    }
+}
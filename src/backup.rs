@@ -1,10 +1,164 @@
+use std::fmt::{self, Display};
+
+use serde::{Deserialize, Serialize};
 use tokio::process::Command;
 
-use crate::{config::BackupConfig, restic::build_restic_command};
+use crate::{
+    config::{BackupConfig, HookConfig},
+    restic::build_restic_command,
+    runner,
+};
 
 pub fn backup(backup: &BackupConfig) -> Command {
     let mut cmd = build_restic_command(backup);
 
-    cmd.args(["--verbose", "backup"]).arg(&backup.src);
+    // Only print the summary, as JSON so it can be picked up for metrics. It's logged in a
+    // readable form once the backup is done, anything else restic prints is logged as it comes.
+    cmd.args(["--json", "--quiet", "backup"]).args(&backup.src);
+
+    for pattern in backup.exclude.iter().flatten() {
+        cmd.arg("--exclude").arg(pattern);
+    }
+
+    for file in backup.exclude_file.iter().flatten() {
+        cmd.arg("--exclude-file").arg(file);
+    }
+
+    if backup.exclude_caches == Some(true) {
+        cmd.arg("--exclude-caches");
+    }
+
+    if backup.one_file_system == Some(true) {
+        cmd.arg("--one-file-system");
+    }
+
+    if let Some(host) = &backup.host {
+        cmd.arg("--host").arg(host);
+    }
+
+    for tag in backup.tags.as_ref().unwrap_or(&backup.default_tags) {
+        cmd.arg("--tag").arg(tag);
+    }
+
     cmd
 }
+
+/// The statistics restic prints at the end of a backup.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BackupSummary {
+    pub snapshot_id: Option<String>,
+    pub files_new: u64,
+    pub files_changed: u64,
+    /// Bytes added to the repository, after deduplication and compression
+    pub data_added: u64,
+    pub total_bytes_processed: u64,
+    /// In seconds
+    pub total_duration: f64,
+}
+
+impl Display for BackupSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} new and {} changed files, added {} of {} processed in {:.1}s, snapshot {}",
+            self.files_new,
+            self.files_changed,
+            Bytes(self.data_added),
+            Bytes(self.total_bytes_processed),
+            self.total_duration,
+            self.snapshot_id.as_deref().unwrap_or("unknown")
+        )
+    }
+}
+
+/// A size in the binary units restic uses.
+struct Bytes(u64);
+
+impl Display for Bytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const UNITS: [&str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+
+        if self.0 < 1024 {
+            return write!(f, "{} B", self.0);
+        }
+
+        let mut size = self.0 as f64 / 1024.0;
+        let mut unit = 0;
+        while size >= 1024.0 && unit < UNITS.len() - 1 {
+            size /= 1024.0;
+            unit += 1;
+        }
+        write!(f, "{size:.2} {}", UNITS[unit])
+    }
+}
+
+/// Find the summary message in the output of `restic backup --json`.
+pub fn parse_summary(output: &[String]) -> Option<BackupSummary> {
+    output.iter().rev().find_map(|line| {
+        let message: serde_json::Value = serde_json::from_str(line).ok()?;
+        if message.get("message_type")? != "summary" {
+            return None;
+        }
+        serde_json::from_value(message).ok()
+    })
+}
+
+pub fn hook(hook: &HookConfig, container_name: Option<&str>) -> Command {
+    match container_name {
+        Some(container_name) => runner::exec(container_name, &hook.command),
+        None => {
+            let mut cmd = Command::new(&hook.command[0]);
+            cmd.args(&hook.command[1..]);
+            cmd
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// As printed by restic 0.17
+    const SUMMARY: &str = r#"{"message_type":"summary","files_new":2,"files_changed":1,"files_unmodified":40,"dirs_new":0,"dirs_changed":2,"dirs_unmodified":5,"data_blobs":3,"tree_blobs":3,"data_added":1572864,"data_added_packed":1048576,"total_files_processed":43,"total_bytes_processed":52428800,"total_duration":2.345678,"backup_start":"2024-05-01T03:00:00.123456789+02:00","backup_end":"2024-05-01T03:00:02.469134789+02:00","snapshot_id":"6e7b21c6f2e94e1c9a3c1f0ed9b0b1a4c2d3e4f5a6b7c8d9e0f1a2b3c4d5e6f7"}"#;
+
+    #[test]
+    fn parses_the_summary_line() {
+        let output = vec![
+            "using parent snapshot 1c2d3e4f".to_string(),
+            r#"{"message_type":"error","error":{"message":"permission denied"},"during":"archival","item":"/data/secret"}"#.to_string(),
+            SUMMARY.to_string(),
+        ];
+
+        let summary = parse_summary(&output).unwrap();
+        assert_eq!(summary.files_new, 2);
+        assert_eq!(summary.files_changed, 1);
+        assert_eq!(summary.data_added, 1572864);
+        assert_eq!(summary.total_bytes_processed, 52428800);
+        assert_eq!(summary.total_duration, 2.345678);
+        assert_eq!(
+            summary.snapshot_id.as_deref(),
+            Some("6e7b21c6f2e94e1c9a3c1f0ed9b0b1a4c2d3e4f5a6b7c8d9e0f1a2b3c4d5e6f7")
+        );
+    }
+
+    #[test]
+    fn no_summary_without_a_summary_line() {
+        let output = vec![
+            "Fatal: unable to open config file".to_string(),
+            r#"{"message_type":"status","percent_done":0.5}"#.to_string(),
+        ];
+
+        assert!(parse_summary(&output).is_none());
+    }
+
+    #[test]
+    fn summary_reads_like_restic() {
+        let summary = parse_summary(&[SUMMARY.to_string()]).unwrap();
+
+        assert_eq!(
+            summary.to_string(),
+            "2 new and 1 changed files, added 1.50 MiB of 50.00 MiB processed in 2.3s, \
+             snapshot 6e7b21c6f2e94e1c9a3c1f0ed9b0b1a4c2d3e4f5a6b7c8d9e0f1a2b3c4d5e6f7"
+        );
+    }
+}
@@ -0,0 +1,17 @@
+use tokio::process::Command;
+
+use crate::{
+    config::{BackupConfig, CheckConfig},
+    restic::build_restic_command,
+};
+
+pub fn check(backup: &BackupConfig, check: &CheckConfig) -> Command {
+    let mut cmd = build_restic_command(backup);
+    cmd.arg("check");
+
+    if let Some(subset) = &check.read_data_subset {
+        cmd.arg("--read-data-subset").arg(subset);
+    }
+
+    cmd
+}
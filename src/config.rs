@@ -1,27 +1,344 @@
-use std::{collections::HashMap, fmt::Display, path::PathBuf, str::FromStr, time::Duration};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt::Display,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
 
-use chrono::{DateTime, Days, TimeZone};
+use anyhow::{bail, Context};
+
+use chrono::{
+    DateTime, Days, FixedOffset, LocalResult, NaiveDate, NaiveDateTime, NaiveTime, TimeZone,
+};
 use cron::Schedule;
 use serde::{Deserialize, Serialize};
-use serde_with::{DeserializeFromStr, SerializeDisplay};
+use serde_with::{serde_as, DeserializeFromStr, DurationSeconds, OneOrMany, SerializeDisplay};
 use strum::{Display, EnumString};
 
+use crate::secrets::resolve_environments;
+
+#[serde_as]
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Config {
-    pub backup: Option<BackupConfig>,
+    /// A single backup target, or a list of named ones
+    #[serde_as(as = "Option<OneOrMany<_>>")]
+    pub backup: Option<Vec<BackupConfig>>,
     pub restore: Option<RestoreConfig>,
-    pub app: AppConfig,
+    pub app: Option<AppConfig>,
+    pub apps: Option<BTreeMap<String, AppConfig>>,
+    /// Name of a podman pod to run all apps in, so they share a network namespace
+    pub pod: Option<String>,
     pub update: Option<UpdateConfig>,
+    /// IANA timezone name used for schedules, defaults to `TZ` or the system timezone
+    pub timezone: Option<String>,
+    /// Directory to persist schedule state in across restarts, nothing is persisted if unset
+    pub state_dir: Option<PathBuf>,
+    /// Where to send notifications about jobs and apps, one channel or a list of them
+    #[serde_as(as = "Option<OneOrMany<_>>")]
+    #[serde(default)]
+    pub notifications: Option<Vec<NotificationConfig>>,
+    /// Serve a status and control API over HTTP, including Prometheus metrics at `/metrics`
+    pub api: Option<ApiConfig>,
+}
+
+/// Environment variables by name. Values can be `${VAR}` to take them from the supervisor's
+/// environment (`$$` for a literal `$`), or `file:<path>` to read them from a file. Those are
+/// redacted from the log, like values written as `secret:<value>`.
+pub type Environments = HashMap<String, String>;
+
+/// A healthchecks.io style monitor URL. `<url>/start` is pinged when the job starts, then the
+/// URL itself when it succeeds or `<url>/fail` with the error when it fails.
+pub type PingUrl = String;
+
+/// Name given to the app when the config uses the single `app` key
+pub const DEFAULT_APP_NAME: &str = "app";
+
+impl Config {
+    pub fn backups(&self) -> &[BackupConfig] {
+        self.backup.as_deref().unwrap_or_default()
+    }
+
+    /// Fill in the values that default to other parts of the config.
+    pub fn apply_defaults(&mut self) -> anyhow::Result<()> {
+        self.resolve_environments()?;
+
+        let app_names: Vec<String> = self
+            .apps_in_start_order()?
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+
+        // Snapshots made before tagging don't have these, so they're never used as a filter
+        for backup in self.backup.iter_mut().flatten() {
+            backup.default_tags = app_names.clone();
+        }
+
+        if let Some(restore) = &mut self.restore {
+            restore
+                .inherit(self.backup.as_deref().unwrap_or_default())
+                .context("Resolving restore from backup target")?;
+        }
+
+        Ok(())
+    }
+
+    /// Expand secret sources in every environment, before anything copies them around.
+    fn resolve_environments(&mut self) -> anyhow::Result<()> {
+        for backup in self.backup.iter_mut().flatten() {
+            resolve_environments(&mut backup.environments, backup.env_file.as_ref())
+                .with_context(|| format!("Resolving environments of backup {}", backup.name()))?;
+        }
+
+        if let Some(restore) = &mut self.restore {
+            resolve_environments(&mut restore.environments, restore.env_file.as_ref())
+                .context("Resolving environments of restore")?;
+        }
+
+        let apps = self.app.iter_mut().map(|app| (DEFAULT_APP_NAME, app));
+        let apps = apps.chain(
+            self.apps
+                .iter_mut()
+                .flatten()
+                .map(|(name, app)| (name.as_str(), app)),
+        );
+
+        for (name, app) in apps {
+            resolve_environments(&mut app.environments, app.env_file.as_ref())
+                .with_context(|| format!("Resolving environments of app {name}"))?;
+        }
+
+        Ok(())
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let apps = self.apps_in_start_order()?;
+
+        if let Some(restore) = &self.restore {
+            if restore.repo.is_none() {
+                bail!("Restore needs a repo, or from_backup to take it from a backup target");
+            }
+
+            if restore.dst.is_none() {
+                bail!("Restore needs a dst, or from_backup to take it from a backup target");
+            }
+        }
+
+        let mut target_names = HashSet::new();
+        for backup in self.backups() {
+            if backup.name.is_none() && self.backups().len() > 1 {
+                bail!("Every backup target needs a name when there are more than one");
+            }
+
+            if !target_names.insert(backup.name()) {
+                bail!("Duplicate backup target {}", backup.name());
+            }
+
+            backup
+                .validate(&apps)
+                .with_context(|| format!("Validating backup target {}", backup.name()))?;
+        }
+
+        if let Some(api) = &self.api {
+            if api.listen.is_some() == api.socket.is_some() {
+                bail!("The API needs exactly one of listen or socket");
+            }
+        }
+
+        for notification in self.notifications.iter().flatten() {
+            match (&notification.webhook, &notification.command) {
+                (Some(_), None) => {}
+                (None, Some(command)) if !command.is_empty() => {
+                    if notification.format.is_some() {
+                        bail!("Notification format only applies to webhooks");
+                    }
+                }
+                (None, Some(_)) => bail!("Notification command can't be empty"),
+                _ => bail!("Notifications need exactly one of webhook or command"),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// All the apps to supervise, ordered so that every app comes after its dependencies.
+    pub fn apps_in_start_order(&self) -> anyhow::Result<Vec<(String, AppConfig)>> {
+        let apps: BTreeMap<String, AppConfig> = match (&self.app, &self.apps) {
+            (Some(_), Some(_)) => bail!("Only one of `app` and `apps` can be specified"),
+            (Some(app), None) => [(DEFAULT_APP_NAME.to_string(), app.clone())].into(),
+            (None, Some(apps)) if !apps.is_empty() => apps.clone(),
+            _ => bail!("At least one app must be specified"),
+        };
+
+        for (name, app) in &apps {
+            for dep in app.depends_on.iter().flatten() {
+                if !apps.contains_key(dep) {
+                    bail!("App {name} depends on unknown app {dep}");
+                }
+            }
+
+            if self.pod.is_some() && app.network_mode.is_some() {
+                bail!("App {name} can't set network_mode when running in a pod");
+            }
+
+            if let Some(healthcheck) = &app.healthcheck {
+                let probes = [
+                    healthcheck.command.is_some(),
+                    healthcheck.http.is_some(),
+                    healthcheck.tcp.is_some(),
+                ];
+
+                if probes.into_iter().filter(|p| *p).count() != 1 {
+                    bail!("Healthcheck of app {name} needs exactly one of command, http or tcp");
+                }
+            }
+        }
+
+        let mut ordered = Vec::with_capacity(apps.len());
+        let mut visited = HashSet::new();
+        let mut visiting = HashSet::new();
+
+        fn visit<'a>(
+            name: &'a str,
+            apps: &'a BTreeMap<String, AppConfig>,
+            visited: &mut HashSet<&'a str>,
+            visiting: &mut HashSet<&'a str>,
+            ordered: &mut Vec<(String, AppConfig)>,
+        ) -> anyhow::Result<()> {
+            if visited.contains(name) {
+                return Ok(());
+            }
+
+            if !visiting.insert(name) {
+                bail!("Circular dependency on app {name}");
+            }
+
+            let app = &apps[name];
+            for dep in app.depends_on.iter().flatten() {
+                visit(dep, apps, visited, visiting, ordered)
+                    .with_context(|| format!("Resolving dependencies of {name}"))?;
+            }
+
+            visiting.remove(name);
+            visited.insert(name);
+            ordered.push((name.to_string(), app.clone()));
+            Ok(())
+        }
+
+        for name in apps.keys() {
+            visit(name, &apps, &mut visited, &mut visiting, &mut ordered)?;
+        }
+
+        Ok(ordered)
+    }
 }
 
+#[serde_as]
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct RestoreConfig {
-    pub repo: String,
-    pub dst: PathBuf,
+    /// Take the repo, environments, snapshot filters and destination from a backup target:
+    /// `true` for the only one, or the name of one. Fields set here still take precedence.
+    pub from_backup: Option<BackupTargetRef>,
+    pub repo: Option<String>,
+    pub dst: Option<PathBuf>,
     pub strategy: Option<RestoreStrategy>,
-    pub environments: Option<HashMap<String, String>>,
+    pub environments: Option<Environments>,
+    /// Dotenv files loaded before `environments`, one or a list
+    #[serde_as(as = "Option<OneOrMany<_>>")]
+    #[serde(default)]
+    pub env_file: Option<Vec<PathBuf>>,
+    /// ID of the snapshot to restore, defaults to the latest one matching the filters
+    pub snapshot: Option<String>,
+    /// Only consider snapshots made before this time, e.g. `2026-10-01T00:00`. Times without
+    /// an offset are in the configured timezone.
+    pub before: Option<PointInTime>,
+    /// Only consider snapshots with all of these tags
+    pub tags: Option<Vec<String>>,
+    pub host: Option<String>,
+    /// Only consider snapshots that include all of these paths
+    pub paths: Option<Vec<PathBuf>>,
+    /// Directory in the snapshot whose contents are restored into `dst`, instead of the whole
+    /// snapshot with its absolute paths
+    pub subfolder: Option<PathBuf>,
+    /// Only restore files matching these patterns
+    pub include: Option<Vec<String>>,
+    pub exclude: Option<Vec<String>>,
+    /// Entries that don't stop dst from counting as empty, defaults to `lost+found`
+    pub ignored_entries: Option<Vec<String>>,
+    /// Check the restored files against the snapshot with `restic restore --verify`
+    pub verify: Option<bool>,
+    /// Attempts after a failed restore before giving up on starting
+    pub retries: Option<u32>,
+    /// Seconds before the first retry, doubled for every further one
+    #[serde_as(as = "Option<DurationSeconds<u64>>")]
+    pub retry_backoff: Option<Duration>,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum BackupTargetRef {
+    Only(bool),
+    Named(String),
+}
+
+impl RestoreConfig {
+    pub fn dst(&self) -> &Path {
+        self.dst
+            .as_deref()
+            .expect("restore dst to be checked by Config::validate")
+    }
+
+    fn inherit(&mut self, backups: &[BackupConfig]) -> anyhow::Result<()> {
+        let backup = match &self.from_backup {
+            None | Some(BackupTargetRef::Only(false)) => return Ok(()),
+            Some(BackupTargetRef::Only(true)) => match backups {
+                [backup] => backup,
+                [] => bail!("There is no backup target to restore from"),
+                _ => bail!("There are multiple backup targets, name the one to restore from"),
+            },
+            Some(BackupTargetRef::Named(name)) => backups
+                .iter()
+                .find(|b| b.name() == name)
+                .with_context(|| format!("Unknown backup target {name}"))?,
+        };
+
+        let dst = match (&self.dst, backup.src.as_slice()) {
+            (Some(dst), _) => dst.clone(),
+            (None, [src]) => src.clone(),
+            (None, _) => bail!(
+                "dst must be specified to restore from backup target {}, which has multiple sources",
+                backup.name()
+            ),
+        };
+
+        if !backup.src.contains(&dst) {
+            bail!(
+                "Restore destination {} doesn't match any source of backup target {}",
+                dst.display(),
+                backup.name()
+            );
+        }
+
+        self.repo.get_or_insert_with(|| backup.repo.clone());
+
+        if let Some(backup_envs) = &backup.environments {
+            let envs = self.environments.get_or_insert_with(Default::default);
+            for (key, value) in backup_envs {
+                envs.entry(key.clone()).or_insert_with(|| value.clone());
+            }
+        }
+
+        self.host = self.host.take().or_else(|| backup.host.clone());
+        self.tags = self.tags.take().or_else(|| backup.tags.clone());
+        self.paths.get_or_insert_with(|| vec![dst.clone()]);
+        self.subfolder.get_or_insert_with(|| dst.clone());
+        self.dst = Some(dst);
+
+        Ok(())
+    }
+}
+
+#[serde_as]
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct AppConfig {
     pub image: String,
@@ -29,59 +346,417 @@ pub struct AppConfig {
     pub volumes: Option<Vec<String>>,
     pub ports: Option<Vec<String>>,
     pub network_mode: Option<NetworkMode>,
-    pub environments: Option<HashMap<String, String>>,
+    pub environments: Option<Environments>,
+    /// Dotenv files loaded before `environments`, one or a list
+    #[serde_as(as = "Option<OneOrMany<_>>")]
+    #[serde(default)]
+    pub env_file: Option<Vec<PathBuf>>,
     pub cap_add: Option<Vec<String>>,
+    /// Apps in the same config that have to be running, and healthy if they have a healthcheck,
+    /// before this one is started
+    pub depends_on: Option<Vec<String>>,
+    pub restart: Option<RestartConfig>,
+    pub healthcheck: Option<HealthcheckConfig>,
+    /// Defaults to `pdrun-<pid>-<app name>`
+    pub container_name: Option<String>,
+}
+
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HealthcheckConfig {
+    /// Command run inside the container with `podman exec`, healthy when it exits successfully
+    pub command: Option<Vec<String>>,
+    /// URL requested from the host, healthy on a successful response
+    pub http: Option<String>,
+    /// `host:port` connected to from the host
+    pub tcp: Option<String>,
+    /// In seconds
+    #[serde_as(as = "Option<DurationSeconds<u64>>")]
+    pub interval: Option<Duration>,
+    /// In seconds
+    #[serde_as(as = "Option<DurationSeconds<u64>>")]
+    pub timeout: Option<Duration>,
+    /// Consecutive failures before the app is considered unhealthy
+    pub retries: Option<u32>,
+    /// Failures within this many seconds after starting don't count
+    #[serde_as(as = "Option<DurationSeconds<u64>>")]
+    pub start_period: Option<Duration>,
+}
+
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RestartConfig {
+    pub policy: RestartPolicy,
+    /// Give up restarting once the app has been restarted this many times within `window`
+    pub max_retries: Option<u32>,
+    /// In seconds
+    #[serde_as(as = "Option<DurationSeconds<u64>>")]
+    pub window: Option<Duration>,
+    /// Delay before the first restart in seconds, doubled for every further restart in `window`
+    #[serde_as(as = "Option<DurationSeconds<u64>>")]
+    pub backoff: Option<Duration>,
+    /// In seconds
+    #[serde_as(as = "Option<DurationSeconds<u64>>")]
+    pub max_backoff: Option<Duration>,
 }
 
+#[derive(
+    Display,
+    EnumString,
+    Debug,
+    Clone,
+    SerializeDisplay,
+    DeserializeFromStr,
+    Copy,
+    PartialEq,
+    Eq,
+    Default,
+)]
+#[strum(serialize_all = "kebab-case")]
+pub enum RestartPolicy {
+    #[default]
+    Never,
+    OnFailure,
+    Always,
+}
+
+/// Name given to the backup target when it isn't named
+pub const DEFAULT_BACKUP_NAME: &str = "backup";
+
+#[serde_as]
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct BackupConfig {
+    /// Required when there are multiple backup targets, also used as the log prefix
+    pub name: Option<String>,
     pub repo: String,
-    pub src: PathBuf,
+    /// One or more paths to back up
+    #[serde_as(as = "OneOrMany<_>")]
+    pub src: Vec<PathBuf>,
+    pub exclude: Option<Vec<String>>,
+    pub exclude_file: Option<Vec<PathBuf>>,
+    pub exclude_caches: Option<bool>,
+    pub one_file_system: Option<bool>,
+    /// Host name recorded in snapshots instead of the machine's
+    pub host: Option<String>,
+    /// Tags added to snapshots and used to find them again. When adding tags to a target that
+    /// already has snapshots, tag those too (`restic tag --add <tags>`) or forget, restore and
+    /// the schedule no longer see them. Without tags, snapshots are tagged with the app names
+    /// but not filtered by them.
+    pub tags: Option<Vec<String>>,
+    /// Tags added to snapshots when `tags` isn't set, see `Config::apply_defaults`
+    #[serde(skip)]
+    pub default_tags: Vec<String>,
     pub interval: Interval,
     pub strategy: Option<BackupStrategy>,
-    pub environments: Option<HashMap<String, String>>,
+    pub environments: Option<Environments>,
+    /// Dotenv files loaded before `environments`, one or a list
+    #[serde_as(as = "Option<OneOrMany<_>>")]
+    #[serde(default)]
+    pub env_file: Option<Vec<PathBuf>>,
+    pub hooks: Option<BackupHooks>,
+    pub retention: Option<RetentionConfig>,
+    pub check: Option<CheckConfig>,
+    pub restore_test: Option<RestoreTestConfig>,
+    /// Run `restic init` on startup if the repository doesn't exist yet
+    pub init_if_missing: Option<bool>,
+    /// Monitor pinged when the backup runs, see `PingUrl`
+    pub ping_url: Option<PingUrl>,
+    /// Attempts after a failed backup before waiting for the next scheduled one
+    pub retries: Option<u32>,
+    /// Seconds before the first retry, doubled for every further one. Defaults to a minute.
+    #[serde_as(as = "Option<DurationSeconds<u64>>")]
+    pub retry_backoff: Option<Duration>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CheckConfig {
+    pub interval: Interval,
+    /// Also read back this much of the pack data, e.g. `10%` or `1/5`
+    pub read_data_subset: Option<String>,
+    /// Monitor pinged when the check runs, see `PingUrl`
+    pub ping_url: Option<PingUrl>,
 }
 
+/// Periodically restore the latest snapshot into a scratch directory to prove it's usable
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RestoreTestConfig {
+    pub interval: Interval,
+    /// Where to create the temporary restore directory. It has to fit a full copy of the
+    /// data, so this isn't defaulted to the temp dir, which is often a tmpfs.
+    pub scratch_dir: PathBuf,
+    /// Command run inside the restored directory, the test fails if it exits unsuccessfully.
+    /// Required when the target excludes anything, the live data can't be compared then.
+    pub verify_command: Option<Vec<String>>,
+    /// How far file count and total size may differ from the live data, in percent
+    pub max_difference: Option<f64>,
+    /// Monitor pinged when the restore test runs, see `PingUrl`
+    pub ping_url: Option<PingUrl>,
+}
+
+impl BackupConfig {
+    pub fn name(&self) -> &str {
+        self.name.as_deref().unwrap_or(DEFAULT_BACKUP_NAME)
+    }
+
+    fn validate(&self, apps: &[(String, AppConfig)]) -> anyhow::Result<()> {
+        if self.src.is_empty() {
+            bail!("At least one source path must be specified");
+        }
+
+        if let Some(retention) = &self.retention {
+            let policies = [
+                retention.keep_last,
+                retention.keep_hourly,
+                retention.keep_daily,
+                retention.keep_weekly,
+                retention.keep_monthly,
+                retention.keep_yearly,
+            ];
+
+            if policies.iter().all(Option::is_none) && retention.keep_within.is_none() {
+                bail!("Backup retention needs at least one keep_* policy");
+            }
+        }
+
+        if let Some(hooks) = &self.hooks {
+            for hook in hooks.pre.iter().chain(&hooks.post).flatten() {
+                if hook.command.is_empty() {
+                    bail!("Backup hook command can't be empty");
+                }
+
+                if let Some(app) = &hook.app {
+                    if !apps.iter().any(|(name, _)| name == app) {
+                        bail!("Backup hook refers to unknown app {app}");
+                    }
+                }
+            }
+        }
+
+        if let Some(test) = &self.restore_test {
+            if test.verify_command.as_ref().is_some_and(Vec::is_empty) {
+                bail!("Restore test verify_command can't be empty");
+            }
+
+            // Comparing with the live data would count the excluded files too
+            let excludes = self.exclude.as_ref().is_some_and(|e| !e.is_empty())
+                || self.exclude_file.as_ref().is_some_and(|e| !e.is_empty())
+                || self.exclude_caches == Some(true)
+                || self.one_file_system == Some(true);
+            if excludes && test.verify_command.is_none() {
+                bail!("Restore test needs a verify_command when the backup excludes anything");
+            }
+
+            if test
+                .max_difference
+                .is_some_and(|d| !(0.0..=100.0).contains(&d))
+            {
+                bail!("Restore test max_difference must be a percentage between 0 and 100");
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RetentionConfig {
+    pub keep_last: Option<u32>,
+    pub keep_hourly: Option<u32>,
+    pub keep_daily: Option<u32>,
+    pub keep_weekly: Option<u32>,
+    pub keep_monthly: Option<u32>,
+    pub keep_yearly: Option<u32>,
+    /// A restic duration like `2y5m7d3h`
+    pub keep_within: Option<String>,
+    /// Forget snapshots on this schedule, instead of after every successful backup
+    pub interval: Option<Interval>,
+    /// Prune on this schedule, instead of every time snapshots are forgotten
+    pub prune_interval: Option<Interval>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BackupHooks {
+    /// Run before the backup starts (and before the app is stopped). A failure aborts the backup.
+    pub pre: Option<Vec<HookConfig>>,
+    /// Run after the backup, and after the app is started again, whether or not the backup worked
+    pub post: Option<Vec<HookConfig>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HookConfig {
+    pub command: Vec<String>,
+    /// Run the command inside this app's container with `podman exec` instead of on the host
+    pub app: Option<String>,
+}
+
+/// The API has no authentication, so it should only be reachable from the host
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ApiConfig {
+    /// Address to listen on, e.g. `127.0.0.1:8080`
+    pub listen: Option<String>,
+    /// Path of a Unix socket to listen on instead
+    pub socket: Option<PathBuf>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NotificationConfig {
+    /// URL to POST notifications to
+    pub webhook: Option<String>,
+    /// Body of webhook requests, defaults to `json`
+    pub format: Option<NotificationFormat>,
+    /// Command run on the host for every notification, with the details in `PDRUN_*`
+    /// environment variables
+    pub command: Option<Vec<String>>,
+    /// Which events to notify about, defaults to `failure` and `app_crashed`
+    pub events: Option<Vec<NotificationEvent>>,
+}
+
+#[derive(
+    Display,
+    EnumString,
+    Debug,
+    Clone,
+    SerializeDisplay,
+    DeserializeFromStr,
+    Copy,
+    PartialEq,
+    Eq,
+    Default,
+)]
+#[strum(serialize_all = "snake_case")]
+pub enum NotificationFormat {
+    /// The notification itself, as a JSON object
+    #[default]
+    Json,
+    Ntfy,
+    Gotify,
+    Slack,
+}
+
+#[derive(
+    Display, EnumString, Debug, Clone, SerializeDisplay, DeserializeFromStr, Copy, PartialEq, Eq,
+)]
+#[strum(serialize_all = "snake_case")]
+pub enum NotificationEvent {
+    /// A backup, restore, update or maintenance job failed
+    Failure,
+    /// A backup, restore or maintenance job succeeded
+    Success,
+    /// An app was restarted with a new image
+    UpdateApplied,
+    /// An app exited unexpectedly or was restarted for being unhealthy
+    AppCrashed,
+}
+
+#[serde_as]
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct UpdateConfig {
     pub interval: Interval,
+    /// Seconds an updated app has to stay up (and pass its healthcheck) before the update is
+    /// considered good. Otherwise the previous image is restored.
+    #[serde_as(as = "Option<DurationSeconds<u64>>")]
+    pub grace_period: Option<Duration>,
+    /// Ask the registry for the image digest with `skopeo` first, and only pull when it's
+    /// different from the local image
+    pub check_registry: Option<bool>,
+    /// Monitor pinged when the update runs, see `PingUrl`
+    pub ping_url: Option<PingUrl>,
 }
 
 impl Default for UpdateConfig {
     fn default() -> Self {
         Self {
             interval: Interval::Daily,
+            grace_period: None,
+            check_registry: None,
+            ping_url: None,
         }
     }
 }
 
 #[derive(
-    Display, EnumString, Debug, Clone, SerializeDisplay, DeserializeFromStr, Copy, PartialEq, Eq,
+    Display,
+    EnumString,
+    Debug,
+    Clone,
+    SerializeDisplay,
+    DeserializeFromStr,
+    Copy,
+    PartialEq,
+    Eq,
+    Default,
 )]
 #[strum(serialize_all = "snake_case")]
 pub enum BackupStrategy {
+    #[default]
     StopApp,
     Live,
 }
 
-impl Default for BackupStrategy {
-    fn default() -> Self {
-        Self::StopApp
-    }
-}
-
 #[derive(
-    Display, EnumString, Debug, Clone, SerializeDisplay, DeserializeFromStr, Copy, PartialEq, Eq,
+    Display,
+    EnumString,
+    Debug,
+    Clone,
+    SerializeDisplay,
+    DeserializeFromStr,
+    Copy,
+    PartialEq,
+    Eq,
+    Default,
 )]
 #[strum(serialize_all = "snake_case")]
 pub enum RestoreStrategy {
+    /// Restore when dst is missing or has nothing in it but `ignored_entries`
+    #[default]
     EmptyDstOnly,
+    /// Restore unless the marker written after a successful restore is in dst
+    MissingMarker,
     Always,
 }
 
-impl Default for RestoreStrategy {
-    fn default() -> Self {
-        Self::EmptyDstOnly
+#[derive(Debug, Clone, Copy, SerializeDisplay, DeserializeFromStr)]
+pub enum PointInTime {
+    Absolute(DateTime<FixedOffset>),
+    Local(NaiveDateTime),
+}
+
+impl PointInTime {
+    pub fn in_timezone<Tz: TimeZone>(&self, tz: &Tz) -> Option<DateTime<Tz>> {
+        match self {
+            PointInTime::Absolute(t) => Some(t.with_timezone(tz)),
+            PointInTime::Local(t) => tz.from_local_datetime(t).earliest(),
+        }
+    }
+}
+
+impl FromStr for PointInTime {
+    type Err = chrono::ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Ok(t) = DateTime::parse_from_rfc3339(s) {
+            return Ok(Self::Absolute(t));
+        }
+
+        if let Ok(date) = NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+            return Ok(Self::Local(date.and_time(NaiveTime::MIN)));
+        }
+
+        NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M:%S")
+            .or_else(|_| NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S"))
+            .or_else(|_| NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M"))
+            .or_else(|_| NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M"))
+            .map(Self::Local)
+    }
+}
+
+impl Display for PointInTime {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PointInTime::Absolute(t) => f.write_str(&t.to_rfc3339()),
+            PointInTime::Local(t) => write!(f, "{}", t.format("%Y-%m-%dT%H:%M:%S")),
+        }
     }
 }
 
@@ -90,7 +765,7 @@ pub enum Interval {
     Hourly,
     Daily,
     Weekly,
-    Custom(Schedule),
+    Custom(Box<Schedule>),
 }
 
 impl FromStr for Interval {
@@ -101,7 +776,7 @@ impl FromStr for Interval {
             "hourly" => Ok(Self::Hourly),
             "daily" => Ok(Self::Daily),
             "weekly" => Ok(Self::Weekly),
-            s => Ok(Self::Custom(s.parse()?)),
+            s => Ok(Self::Custom(Box::new(s.parse()?))),
         }
     }
 }
@@ -124,9 +799,9 @@ impl Interval {
         <Tz as TimeZone>::Offset: Copy,
     {
         let next = match self {
-            Interval::Hourly => Some(last.unwrap_or_else(|| now) + chrono::Duration::hours(1)),
-            Interval::Daily => last.unwrap_or_else(|| now).checked_add_days(Days::new(1)),
-            Interval::Weekly => last.unwrap_or_else(|| now).checked_add_days(Days::new(7)),
+            Interval::Hourly => Some(last.unwrap_or(now) + chrono::Duration::hours(1)),
+            Interval::Daily => add_days(last.unwrap_or(now), 1),
+            Interval::Weekly => add_days(last.unwrap_or(now), 7),
             Interval::Custom(s) => s.after_owned(now).next(),
         };
 
@@ -138,9 +813,219 @@ impl Interval {
     }
 }
 
+/// Add calendar days in the local timezone. Unlike `DateTime::checked_add_days`, this doesn't
+/// give up when the resulting wall-clock time is skipped or repeated by a DST transition.
+fn add_days<Tz>(from: DateTime<Tz>, days: u64) -> Option<DateTime<Tz>>
+where
+    Tz: TimeZone,
+{
+    let local = from.naive_local().checked_add_days(Days::new(days))?;
+
+    match from.timezone().from_local_datetime(&local) {
+        LocalResult::Single(next) => Some(next),
+        LocalResult::Ambiguous(earliest, _) => Some(earliest),
+        LocalResult::None => from.checked_add_signed(chrono::Duration::days(days as i64)),
+    }
+}
+
 #[derive(Display, EnumString, Debug, Clone, SerializeDisplay, DeserializeFromStr)]
 #[strum(serialize_all = "snake_case")]
 pub enum NetworkMode {
     Host,
     Bridge,
 }
+
+#[cfg(test)]
+mod tests {
+    use chrono_tz::{Europe::Berlin, Tz};
+
+    use super::*;
+
+    fn berlin(s: &str) -> DateTime<Tz> {
+        let local = chrono::NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M").unwrap();
+        Berlin.from_local_datetime(&local).earliest().unwrap()
+    }
+
+    fn next_after(interval: &str, last: &str) -> Duration {
+        let interval: Interval = interval.parse().unwrap();
+        let last = berlin(last);
+        interval.next(Some(last), last).unwrap()
+    }
+
+    #[test]
+    fn daily_keeps_wall_clock_time_across_spring_forward() {
+        // Clocks go from 02:00 to 03:00 on 2026-03-29, so that day is 23 hours long
+        assert_eq!(
+            next_after("daily", "2026-03-28 10:00"),
+            Duration::from_secs(23 * 3600)
+        );
+    }
+
+    #[test]
+    fn daily_into_skipped_hour_adds_a_full_day() {
+        // 02:30 doesn't exist on 2026-03-29
+        assert_eq!(
+            next_after("daily", "2026-03-28 02:30"),
+            Duration::from_secs(24 * 3600)
+        );
+    }
+
+    #[test]
+    fn daily_keeps_wall_clock_time_across_fall_back() {
+        // Clocks go from 03:00 back to 02:00 on 2026-10-25, so that day is 25 hours long
+        assert_eq!(
+            next_after("daily", "2026-10-24 10:00"),
+            Duration::from_secs(25 * 3600)
+        );
+    }
+
+    #[test]
+    fn daily_into_repeated_hour_picks_the_first() {
+        // 02:30 happens twice on 2026-10-25, the first time is still in summer time
+        assert_eq!(
+            next_after("daily", "2026-10-24 02:30"),
+            Duration::from_secs(24 * 3600)
+        );
+    }
+
+    #[test]
+    fn weekly_across_spring_forward() {
+        assert_eq!(
+            next_after("weekly", "2026-03-26 10:00"),
+            Duration::from_secs((7 * 24 - 1) * 3600)
+        );
+    }
+
+    #[test]
+    fn cron_uses_local_time_across_spring_forward() {
+        assert_eq!(
+            next_after("0 0 9 * * *", "2026-03-28 12:00"),
+            Duration::from_secs(20 * 3600)
+        );
+    }
+
+    #[test]
+    fn cron_uses_local_time_across_fall_back() {
+        assert_eq!(
+            next_after("0 0 9 * * *", "2026-10-24 12:00"),
+            Duration::from_secs(22 * 3600)
+        );
+    }
+
+    #[test]
+    fn cron_in_skipped_hour_waits_for_the_next_day() {
+        // There's no 02:30 on 2026-03-29, so the next run is 02:30 CEST on the 30th
+        assert_eq!(
+            next_after("0 30 2 * * *", "2026-03-28 12:00"),
+            Duration::from_secs(37 * 3600 + 1800)
+        );
+    }
+
+    fn backups(yaml: &str) -> Vec<BackupConfig> {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn inherit(restore: &str, backups: &[BackupConfig]) -> anyhow::Result<RestoreConfig> {
+        let mut restore: RestoreConfig = serde_yaml::from_str(restore).unwrap();
+        restore.inherit(backups)?;
+        Ok(restore)
+    }
+
+    const TWO_TARGETS: &str = "
+- name: db
+  repo: /repo/db
+  src: /data/db
+  interval: daily
+- name: files
+  repo: /repo/files
+  src: [/data/files, /data/uploads]
+  host: files-host
+  tags: [files]
+  interval: daily
+";
+
+    #[test]
+    fn inherit_needs_a_target() {
+        let err = inherit("from_backup: true", &[]).unwrap_err();
+        assert_eq!(err.to_string(), "There is no backup target to restore from");
+    }
+
+    #[test]
+    fn inherit_needs_a_name_with_several_targets() {
+        let err = inherit("from_backup: true", &backups(TWO_TARGETS)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "There are multiple backup targets, name the one to restore from"
+        );
+    }
+
+    #[test]
+    fn inherit_rejects_unknown_names() {
+        let err = inherit("from_backup: web", &backups(TWO_TARGETS)).unwrap_err();
+        assert_eq!(err.to_string(), "Unknown backup target web");
+    }
+
+    #[test]
+    fn inherit_needs_dst_with_several_sources() {
+        let err = inherit("from_backup: files", &backups(TWO_TARGETS)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "dst must be specified to restore from backup target files, which has multiple sources"
+        );
+    }
+
+    #[test]
+    fn inherit_needs_dst_to_be_a_source() {
+        let restore = "{ from_backup: files, dst: /data/other }";
+        let err = inherit(restore, &backups(TWO_TARGETS)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Restore destination /data/other doesn't match any source of backup target files"
+        );
+    }
+
+    #[test]
+    fn inherit_takes_the_only_target() {
+        let targets = backups(TWO_TARGETS);
+        let restore = inherit("from_backup: true", &targets[..1]).unwrap();
+
+        assert_eq!(restore.repo.as_deref(), Some("/repo/db"));
+        assert_eq!(restore.dst(), Path::new("/data/db"));
+        assert_eq!(restore.paths, Some(vec![PathBuf::from("/data/db")]));
+        assert_eq!(restore.subfolder, Some(PathBuf::from("/data/db")));
+    }
+
+    #[test]
+    fn explicit_fields_override_inherited_ones() {
+        let restore = "
+from_backup: files
+dst: /data/uploads
+repo: /repo/elsewhere
+host: other-host
+tags: [manual]
+paths: [/data]
+subfolder: /data/uploads/public
+";
+        let restore = inherit(restore, &backups(TWO_TARGETS)).unwrap();
+
+        assert_eq!(restore.repo.as_deref(), Some("/repo/elsewhere"));
+        assert_eq!(restore.host.as_deref(), Some("other-host"));
+        assert_eq!(restore.tags, Some(vec!["manual".to_string()]));
+        assert_eq!(restore.paths, Some(vec![PathBuf::from("/data")]));
+        assert_eq!(
+            restore.subfolder,
+            Some(PathBuf::from("/data/uploads/public"))
+        );
+    }
+
+    #[test]
+    fn unset_fields_are_inherited() {
+        let restore = "{ from_backup: files, dst: /data/uploads }";
+        let restore = inherit(restore, &backups(TWO_TARGETS)).unwrap();
+
+        assert_eq!(restore.repo.as_deref(), Some("/repo/files"));
+        assert_eq!(restore.host.as_deref(), Some("files-host"));
+        assert_eq!(restore.tags, Some(vec!["files".to_string()]));
+        assert_eq!(restore.paths, Some(vec![PathBuf::from("/data/uploads")]));
+    }
+}
@@ -0,0 +1,76 @@
+use std::{process::Stdio, time::Duration};
+
+use anyhow::{bail, Context};
+use tokio::{net::TcpStream, time::timeout};
+
+use crate::{config::HealthcheckConfig, runner};
+
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(30);
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_RETRIES: u32 = 3;
+
+impl HealthcheckConfig {
+    pub fn interval(&self) -> Duration {
+        self.interval.unwrap_or(DEFAULT_INTERVAL)
+    }
+
+    pub fn retries(&self) -> u32 {
+        self.retries.unwrap_or(DEFAULT_RETRIES)
+    }
+
+    pub fn start_period(&self) -> Duration {
+        self.start_period.unwrap_or_default()
+    }
+}
+
+/// Run the probe once, succeeding only if it passes within the configured timeout.
+pub async fn check(
+    client: &reqwest::Client,
+    config: &HealthcheckConfig,
+    container_name: &str,
+) -> anyhow::Result<()> {
+    let limit = config.timeout.unwrap_or(DEFAULT_TIMEOUT);
+
+    timeout(limit, probe(client, config, container_name))
+        .await
+        .with_context(|| format!("Healthcheck timed out after {limit:?}"))?
+}
+
+async fn probe(
+    client: &reqwest::Client,
+    config: &HealthcheckConfig,
+    container_name: &str,
+) -> anyhow::Result<()> {
+    if let Some(command) = &config.command {
+        let output = runner::exec(container_name, command)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .output()
+            .await
+            .context("Running healthcheck command")?;
+
+        if !output.status.success() {
+            bail!(
+                "Healthcheck command exited with {}: {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+    } else if let Some(url) = &config.http {
+        client
+            .get(url)
+            .send()
+            .await
+            .with_context(|| format!("Requesting {url}"))?
+            .error_for_status()
+            .with_context(|| format!("Requesting {url}"))?;
+    } else if let Some(addr) = &config.tcp {
+        TcpStream::connect(addr)
+            .await
+            .with_context(|| format!("Connecting to {addr}"))?;
+    }
+
+    Ok(())
+}
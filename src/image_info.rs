@@ -1,41 +1,90 @@
 use std::process::Stdio;
 
-use anyhow::Context;
-use chrono::{DateTime, Utc};
+use anyhow::{bail, Context};
 use serde::Deserialize;
-use tokio::{io::AsyncReadExt, process::Command};
+use tokio::process::Command;
 
 #[derive(Deserialize)]
-struct ImageInfo {
-    #[serde(rename = "Created")]
-    created: DateTime<Utc>,
+pub struct ImageInfo {
+    #[serde(rename = "Id")]
+    pub id: String,
+    #[serde(rename = "Digest")]
+    pub digest: String,
+    #[serde(rename = "RepoDigests", default)]
+    pub repo_digests: Vec<String>,
 }
 
-pub async fn image_creation_time(image_name: &str) -> anyhow::Result<Option<DateTime<Utc>>> {
-    let mut cmd = Command::new("podman");
-    cmd.arg("inspect")
+impl ImageInfo {
+    /// Whether the local image was pulled from the manifest (or manifest list) with this digest.
+    pub fn has_digest(&self, digest: &str) -> bool {
+        self.digest == digest
+            || self
+                .repo_digests
+                .iter()
+                .any(|d| d.rsplit_once('@').map(|(_, d)| d) == Some(digest))
+    }
+}
+
+/// Look up a local image, `None` if there's no image by that name.
+pub async fn inspect_image(image_name: &str) -> anyhow::Result<Option<ImageInfo>> {
+    let output = Command::new("podman")
+        .args(["image", "inspect"])
         .arg(image_name)
         .args(["--format", "json"])
         .kill_on_drop(true)
-        .stdout(Stdio::piped());
-
-    let mut child = cmd.spawn().expect("to spawn a child process");
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output()
+        .await
+        .context("Running podman image inspect")?;
 
-    let status = child.wait().await.context("Waiting for child process")?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if stderr.contains("image not known") {
+            return Ok(None);
+        }
 
-    if !status.success() {
-        return Ok(None);
+        bail!(
+            "podman image inspect exited with {}: {}",
+            output.status,
+            stderr.trim()
+        );
     }
 
-    let mut stdout = child.stdout.take().context("To take stdout from process")?;
-    let mut json = Default::default();
+    let results: Vec<ImageInfo> =
+        serde_json::from_slice(&output.stdout).context("Deserialize image info")?;
+
+    Ok(results.into_iter().next())
+}
 
-    stdout
-        .read_to_string(&mut json)
+#[derive(Deserialize)]
+struct RemoteImageInfo {
+    #[serde(rename = "Digest")]
+    digest: String,
+}
+
+/// Ask the registry for the digest `image_name` currently points to, without pulling it.
+pub async fn remote_digest(image_name: &str) -> anyhow::Result<String> {
+    let output = Command::new("skopeo")
+        .args(["inspect", "--no-tags"])
+        .arg(format!("docker://{image_name}"))
+        .kill_on_drop(true)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output()
         .await
-        .context("Reading output")?;
+        .context("Running skopeo inspect")?;
+
+    if !output.status.success() {
+        bail!(
+            "skopeo inspect exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
 
-    let results: Vec<ImageInfo> = serde_json::from_str(&json).context("Deserialize image info")?;
+    let info: RemoteImageInfo =
+        serde_json::from_slice(&output.stdout).context("Deserialize remote image info")?;
 
-    Ok(results.into_iter().next().map(|s| s.created))
+    Ok(info.digest)
 }
@@ -1,14 +1,46 @@
+use std::sync::Mutex;
+
+/// Values that must never show up in the output, e.g. passwords resolved from secret files
+static SECRETS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Values shorter than this aren't redacted, so that e.g. a `DEBUG=1` in an env file doesn't
+/// blank out every `1` in the log
+const MIN_SECRET_LEN: usize = 4;
+
+pub fn add_secret(value: &str) {
+    if value.len() < MIN_SECRET_LEN {
+        return;
+    }
+
+    let mut secrets = SECRETS.lock().unwrap();
+    if !secrets.iter().any(|s| s == value) {
+        secrets.push(value.to_string());
+        // Replace longer secrets first, in case one contains another
+        secrets.sort_by_key(|s| std::cmp::Reverse(s.len()));
+    }
+}
+
+pub fn redact(mut message: String) -> String {
+    for secret in SECRETS.lock().unwrap().iter() {
+        if message.contains(secret.as_str()) {
+            message = message.replace(secret.as_str(), "[REDACTED]");
+        }
+    }
+
+    message
+}
+
 macro_rules! logPrint {
     ($target:expr, $fmt:tt $(,$arg:expr)*) => {
         let message = format!("[{}] {}", $target, format!($fmt $(,$arg)*));
-        println!("{message}");
+        println!("{}", $crate::log::redact(message));
     };
 }
 
 macro_rules! elogPrint {
     ($target:expr, $fmt:tt $(,$arg:expr)*) => {
         let message = format!("[{}] {}", $target, format!($fmt $(,$arg)*));
-        eprintln!("{message}");
+        eprintln!("{}", $crate::log::redact(message));
     };
 }
 
@@ -1,38 +1,66 @@
+mod api;
 mod backup;
+mod check;
 mod config;
+mod health;
 mod image_info;
 
 mod log;
+mod metrics;
+mod notify;
+mod ping;
 mod process;
 mod restic;
+mod restore_test;
 mod restores;
+mod retention;
 mod runner;
+mod secrets;
+mod stack;
+mod state;
+mod targets;
 mod tz;
 
 use std::{
+    cell::RefCell,
     future::pending,
     io::BufReader,
     path::PathBuf,
     process::{ExitCode, ExitStatus},
+    rc::Rc,
+    time::Duration,
 };
 
 use anyhow::{bail, Context};
+use api::Response;
 use async_shutdown::Shutdown;
-use chrono::Utc;
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
 use clap::Parser;
-use config::{AppConfig, BackupConfig, RestoreConfig};
+use config::{BackupConfig, RestoreConfig};
+use derive_more::Display;
+use restic::ResticError;
 use restores::restore;
 use runner::pull_image;
+use serde_json::json;
+use stack::{AppStatus, Stack};
+use state::{ImageState, State, StateFile};
+use targets::{run_backup_jobs, BackupJob, BackupTarget, Reporting};
 use tokio::{
     select,
     signal::ctrl_c,
     task::{spawn_local, LocalSet},
-    time::{sleep_until, Instant},
+    time::{sleep, sleep_until, Instant},
 };
 use tz::current_timezone;
 
-use crate::process::Process;
-use log::logPrint;
+use crate::process::{run_to_end, Process};
+use log::{elogPrint, logPrint};
+use notify::{Notification, Notifier};
+use ping::Pinger;
+
+const DEFAULT_UPDATE_GRACE_PERIOD: Duration = Duration::from_secs(30);
+const DEFAULT_RESTORE_RETRY_BACKOFF: Duration = Duration::from_secs(10);
 
 /// A CLI tool to run your podman container with backup and auto update
 #[derive(Parser)]
@@ -40,31 +68,81 @@ use log::logPrint;
 struct Cli {
     /// Path to the config file
     config: PathBuf,
+    /// Send a test notification to every configured channel and exit
+    #[arg(long)]
+    test_notifications: bool,
+}
+
+fn main() -> ExitCode {
+    match try_main() {
+        Ok(code) => code,
+        Err(err) => {
+            // Through the logger, so that secrets in error messages are redacted
+            elogPrint!("supervisor", "Error: {err:?}");
+            ExitCode::FAILURE
+        }
+    }
 }
 
-fn main() -> anyhow::Result<ExitCode> {
+fn try_main() -> anyhow::Result<ExitCode> {
     let _ = dotenvy::dotenv();
 
     let Cli {
         config: config_path,
+        test_notifications,
     } = Cli::parse();
 
     let config = std::fs::File::open(&config_path)
         .with_context(|| format!("Opening {}", config_path.display()))?;
 
-    let config: config::Config =
+    let mut config: config::Config =
         serde_yaml::from_reader(BufReader::new(config)).context("Reading config file")?;
 
+    config
+        .apply_defaults()
+        .and_then(|_| config.validate())
+        .context("Validating config file")?;
+
+    let tz = current_timezone(config.timezone.as_deref()).context("Resolving timezone")?;
+
+    // Named after the config file, so that several stacks can share a state dir
+    let state_path = config.state_dir.as_ref().map(|dir| {
+        let name = config_path.file_stem().unwrap_or(config_path.as_os_str());
+        dir.join(format!("{}.json", name.to_string_lossy()))
+    });
+    let state = StateFile::load(state_path).context("Loading state")?;
+    let notifier = Notifier::new(config.notifications.clone().unwrap_or_default())?;
+    let pinger = Pinger::new()?;
+
     let rt = tokio::runtime::Builder::new_current_thread()
         .enable_all()
         .build()
         .expect("to build a runtime");
 
+    if test_notifications {
+        LocalSet::new()
+            .block_on(&rt, notifier.test())
+            .context("Sending test notification")?;
+        logPrint!("supervisor", "Test notification sent");
+        return Ok(ExitCode::SUCCESS);
+    }
+
     let status: u8 = LocalSet::new()
         .block_on(&rt, async move {
             let shutdown = Shutdown::new();
             spawn_local(monitor_ctrl_c(shutdown.clone()));
-            run(config, shutdown.clone()).await
+            let status = run(
+                config,
+                tz,
+                state,
+                notifier.clone(),
+                pinger.clone(),
+                shutdown.clone(),
+            )
+            .await;
+            notifier.flush().await;
+            pinger.flush().await;
+            status
         })?
         .code()
         .unwrap_or(1)
@@ -74,30 +152,141 @@ fn main() -> anyhow::Result<ExitCode> {
     Ok(ExitCode::from(status))
 }
 
-async fn restore_if_needed(backup: &RestoreConfig, shutdown: Shutdown) -> anyhow::Result<()> {
-    if backup.dst.exists() && backup.strategy != Some(config::RestoreStrategy::Always) {
+async fn restore_if_needed(
+    backup: &RestoreConfig,
+    tz: &Tz,
+    notifier: &Notifier,
+    shutdown: Shutdown,
+) -> anyhow::Result<()> {
+    if !restores::needs_restore(backup).context("Checking restore destination")? {
         logPrint!(
             "supervisor",
-            "Directory {} exists, skipping restore",
-            backup.dst.display()
+            "Directory {} doesn't need restoring ({}), skipping restore",
+            backup.dst().display(),
+            backup.strategy.unwrap_or_default()
         );
         return Ok(());
     }
 
-    let mut process =
-        Process::new("restore", restore(backup), shutdown).context("Starting restoring process")?;
+    let started = Instant::now();
+    let result = restore_with_retries(backup, tz, shutdown).await;
+    notifier.notify(Notification::job(
+        "restore",
+        &backup.dst().display().to_string(),
+        started.elapsed(),
+        &result,
+    ));
+    result
+}
 
-    process
-        .wait()
+async fn restore_with_retries(
+    backup: &RestoreConfig,
+    tz: &Tz,
+    shutdown: Shutdown,
+) -> anyhow::Result<()> {
+    let retries = backup.retries.unwrap_or_default();
+    let mut backoff = backup
+        .retry_backoff
+        .unwrap_or(DEFAULT_RESTORE_RETRY_BACKOFF);
+    let mut attempt = 0;
+
+    loop {
+        let err = match try_restore(backup, tz, shutdown.clone()).await {
+            Ok(()) => return Ok(()),
+            Err(err) if attempt >= retries || shutdown.shutdown_started() => return Err(err),
+            Err(err) => err,
+        };
+
+        attempt += 1;
+        elogPrint!(
+            "supervisor",
+            "Restore failed, retrying in {backoff:?} ({attempt}/{retries}): {err:?}"
+        );
+
+        if shutdown.wrap_cancel(sleep(backoff)).await.is_none() {
+            return Err(err);
+        }
+        backoff *= 2;
+    }
+}
+
+async fn try_restore(backup: &RestoreConfig, tz: &Tz, shutdown: Shutdown) -> anyhow::Result<()> {
+    let snapshot = restores::find_snapshot(backup, tz)
         .await
-        .context("Waiting for restoring process")?;
+        .context("Finding snapshot to restore")?;
+
+    let staging = restores::staging_dir(backup)?;
+
+    logPrint!(
+        "supervisor",
+        "Restoring snapshot {} taken at {} into {} via {}",
+        snapshot.id,
+        snapshot.time.with_timezone(tz),
+        backup.dst().display(),
+        staging.display()
+    );
+
+    let result = run_to_end("restore", restore(backup, &snapshot, &staging), shutdown)
+        .await
+        .and_then(|_| restores::move_into_place(backup, &staging));
+
+    if let Err(err) = result {
+        if let Err(cleanup_err) = restores::remove_path(&staging) {
+            elogPrint!(
+                "supervisor",
+                "Unable to clean up failed restore: {cleanup_err:?}"
+            );
+        }
+        return Err(err);
+    }
 
+    if backup.strategy == Some(config::RestoreStrategy::MissingMarker) {
+        restores::write_marker(backup)?;
+    }
+
+    logPrint!("supervisor", "Restored snapshot {}", snapshot.id);
     Ok(())
 }
 
+async fn init_repo_if_missing(
+    backup: &BackupConfig,
+    restore: Option<&RestoreConfig>,
+    shutdown: Shutdown,
+) -> anyhow::Result<()> {
+    if restic::repo_exists(backup)
+        .await
+        .context("Checking whether the repository exists")?
+    {
+        return Ok(());
+    }
+
+    // A missing repo the restore section points at means something is misconfigured, and
+    // initializing it would paper over that
+    if restore.is_some_and(|r| r.repo.as_deref() == Some(backup.repo.as_str())) {
+        bail!(
+            "Repository {} doesn't exist but is expected to hold data to restore, not initializing it",
+            backup.repo
+        );
+    }
+
+    logPrint!(
+        "supervisor",
+        "Repository {} for {} doesn't exist, initializing it",
+        backup.repo,
+        backup.name()
+    );
+
+    run_to_end(
+        &format!("{}:init", backup.name()),
+        restic::init(backup),
+        shutdown,
+    )
+    .await
+}
+
 async fn sleep_until_or_forever(until: Option<Instant>) {
     match until {
-        Some(until) => sleep_until(until.into()).await,
+        Some(until) => sleep_until(until).await,
         None => pending().await,
     }
 }
@@ -108,137 +297,602 @@ async fn monitor_ctrl_c(shutdown: Shutdown) {
     shutdown.shutdown();
 }
 
-async fn start_backup(
-    backup: &BackupConfig,
-    app: &AppConfig,
-    shutdown: Shutdown,
-    mut app_process: Process,
-) -> anyhow::Result<Process> {
-    let stopping_app = backup.strategy.unwrap_or_default() == config::BackupStrategy::StopApp;
+/// Something the run loop does when a timer fires or a client asks for it.
+enum Action {
+    Jobs(Vec<(usize, BackupJob)>),
+    Update,
+    Restart(String),
+}
 
-    if stopping_app {
-        logPrint!("supervisor", "Stopping app before starting backup");
-        let _ = app_process.terminate_and_wait().await;
+fn action_for(
+    command: api::Command,
+    targets: &[BackupTarget],
+    stack: &Stack,
+) -> Result<Action, Response> {
+    let jobs = |name: Option<String>, job: BackupJob| {
+        let due: Vec<_> = targets
+            .iter()
+            .enumerate()
+            .filter(|(_, t)| name.as_deref().is_none_or(|name| t.name() == name))
+            .filter(|(_, t)| t.is_scheduled(job))
+            .map(|(index, _)| (index, job))
+            .collect();
+
+        match (due.is_empty(), name) {
+            (false, _) => Ok(Action::Jobs(due)),
+            (true, Some(name)) => Err(not_found(format!(
+                "No backup target {name} with a {job} job"
+            ))),
+            (true, None) => Err(not_found(format!("No backup target has a {job} job"))),
+        }
+    };
+
+    match command {
+        api::Command::Backup(name) => jobs(name, BackupJob::Backup),
+        api::Command::Check(name) => jobs(name, BackupJob::Check),
+        api::Command::Update => Ok(Action::Update),
+        api::Command::Restart(name) if stack.container_name(&name).is_some() => {
+            Ok(Action::Restart(name))
+        }
+        api::Command::Restart(name) => Err(not_found(format!("No app {name}"))),
     }
+}
 
-    let mut process = Process::new("backup", backup::backup(backup), shutdown.clone())
-        .context("Starting backup process")?;
+fn not_found(message: String) -> Response {
+    Response::json(404, json!({ "error": message }))
+}
 
-    if !process
-        .wait()
-        .await
-        .context("Waiting for backup process")?
-        .success()
-    {
-        bail!("Failed backing up app");
-    }
+/// What the run loop last published for the API to answer queries from, so that they don't
+/// wait for a running job. The apps' statuses come from the stack directly.
+#[derive(Default)]
+struct Snapshot {
+    targets: Vec<BackupTarget>,
+    state: State,
+    last_update: Option<DateTime<Tz>>,
+}
 
-    if stopping_app {
-        logPrint!("supervisor", "Starting app after backup");
-        app_process =
-            Process::new("app", runner::run_app(app), shutdown).context("Starting app process")?;
+fn answer(
+    query: api::Query,
+    apps: Vec<AppStatus>,
+    snapshot: &Snapshot,
+    update: &config::UpdateConfig,
+    tz: Tz,
+) -> Response {
+    match query {
+        api::Query::Status => Response::ok(status(apps, snapshot, update, tz)),
+        api::Query::Metrics => {
+            let now = Utc::now().with_timezone(&tz);
+            Response::text(metrics::render(
+                &apps,
+                &snapshot.targets,
+                &snapshot.state,
+                now,
+            ))
+        }
     }
+}
 
-    Ok(app_process)
+/// What the supervisor is doing: its apps, and when each job last ran and runs next.
+fn status(
+    apps: Vec<AppStatus>,
+    snapshot: &Snapshot,
+    update: &config::UpdateConfig,
+    tz: Tz,
+) -> serde_json::Value {
+    let now = Utc::now().with_timezone(&tz);
+    let Snapshot {
+        targets,
+        state,
+        last_update,
+    } = snapshot;
+
+    let apps: Vec<_> = apps
+        .into_iter()
+        .map(|app| {
+            let deployed = state.images.get(&app.name);
+            let mut app = json!(app);
+            app["image_id"] = json!(deployed.map(|i| &i.id));
+            app["digest"] = json!(deployed.map(|i| &i.digest));
+            app
+        })
+        .collect();
+
+    let backups: Vec<_> = targets
+        .iter()
+        .map(|target| {
+            let jobs: serde_json::Map<_, _> = BackupJob::ALL
+                .into_iter()
+                .filter(|job| target.is_scheduled(*job))
+                .map(|job| {
+                    let last = state.targets.get(target.name()).and_then(|j| j.get(&job));
+                    let next = target.next_run(job, now).map(|d| d.as_secs_f64());
+                    (job.to_string(), json!({ "last": last, "next_in": next }))
+                })
+                .collect();
+
+            json!({ "name": target.name(), "jobs": jobs })
+        })
+        .collect();
+
+    let next_update = update
+        .interval
+        .next(*last_update, now)
+        .map(|d| d.as_secs_f64());
+
+    json!({
+        "timezone": tz.to_string(),
+        "apps": apps,
+        "backups": backups,
+        "update": { "last": state.update, "next_in": next_update },
+    })
+}
+
+/// How the jobs run on request went, as recorded in the state.
+fn job_outcomes(
+    targets: &[BackupTarget],
+    due: &[(usize, BackupJob)],
+    state: &StateFile,
+) -> serde_json::Value {
+    due.iter()
+        .map(|(index, job)| {
+            let name = targets[*index].name();
+            json!({
+                "target": name,
+                "job": job.to_string(),
+                "outcome": state.state.targets.get(name).and_then(|j| j.get(job)),
+            })
+        })
+        .collect()
 }
 
 async fn start_update(
-    app: &AppConfig,
-    mut app_process: Process,
+    update: &config::UpdateConfig,
+    stack: &mut Stack,
+    state: &mut State,
+    notifier: &Notifier,
     shutdown: Shutdown,
-) -> anyhow::Result<Process> {
-    let old_time = image_info::image_creation_time(&app.image)
-        .await
-        .context("Getting image creation time")?;
+) -> anyhow::Result<()> {
+    let mut rollback = None;
+
+    let apps: Vec<_> = stack
+        .apps()
+        .map(|(name, app)| (name.to_string(), app.clone()))
+        .collect();
+
+    for (name, app) in apps {
+        let old_image = image_info::inspect_image(&app.image)
+            .await
+            .context("Inspecting image")?;
+
+        if let (Some(old_image), Some(true)) = (&old_image, update.check_registry) {
+            match image_info::remote_digest(&app.image).await {
+                Ok(digest) if old_image.has_digest(&digest) => {
+                    logPrint!(
+                        "supervisor",
+                        "Image for {name} is up to date with registry digest {digest}"
+                    );
+                    continue;
+                }
+                Ok(digest) => {
+                    logPrint!(
+                        "supervisor",
+                        "Registry has digest {digest} for {}, local is {}",
+                        app.image,
+                        old_image.digest
+                    );
+                }
+                Err(err) => {
+                    elogPrint!(
+                        "supervisor",
+                        "Unable to check registry for {}, pulling instead: {err:?}",
+                        app.image
+                    );
+                }
+            }
+        }
+
+        logPrint!("supervisor", "Pulling latest image for {}", app.image);
+
+        let mut process = Process::new("update", pull_image(&app), shutdown.clone())
+            .context("Starting update process")?;
+
+        if !process
+            .wait()
+            .await
+            .context("Waiting for update process")?
+            .success()
+        {
+            bail!("Failed pulling {}", app.image);
+        }
 
-    logPrint!("supervisor", "Pulling latest image for {}", app.image);
+        let new_image = image_info::inspect_image(&app.image)
+            .await
+            .context("Inspecting image")?;
+
+        let (old_image, new_image) = match (old_image, new_image) {
+            (_, None) => {
+                logPrint!("supervisor", "Image for {name} not found after pulling");
+                continue;
+            }
+            (Some(old), Some(new)) if new.id == old.id => {
+                logPrint!(
+                    "supervisor",
+                    "Image for {name} not updated ({}). Do nothing",
+                    new.digest
+                );
+                let applied_at = state.images.get(&name).and_then(|i| i.applied_at);
+                state.images.insert(
+                    name,
+                    ImageState {
+                        applied_at,
+                        ..ImageState::from(&new)
+                    },
+                );
+                continue;
+            }
+            (old, Some(new)) => (old, new),
+        };
+
+        logPrint!(
+            "supervisor",
+            "Image for {name} changed from {} to {}",
+            old_image.as_ref().map_or("none", |i| i.digest.as_str()),
+            new_image.digest
+        );
+
+        if state.bad_images.contains(&new_image.id) {
+            logPrint!(
+                "supervisor",
+                "Image {} for {name} failed to start before, not deploying it again",
+                new_image.id
+            );
 
-    let mut process = Process::new("update", pull_image(&app), shutdown.clone())
-        .context("Starting update process")?;
-    process.wait().await.context("Waiting for update process")?;
+            if let Some(old_image) = &old_image {
+                retag_image(&old_image.id, &app.image, shutdown.clone()).await?;
+            }
+            continue;
+        }
+
+        logPrint!("supervisor", "Image updated, restarting {name}");
+        stack.restart(&name).await.context(AppDown(name.clone()))?;
+
+        let grace_period = update.grace_period.unwrap_or(DEFAULT_UPDATE_GRACE_PERIOD);
+        let Err(err) = stack.wait_stable(&name, grace_period).await else {
+            notifier.notify(Notification::update_applied(&name, &new_image.digest));
+            state.images.insert(
+                name,
+                ImageState {
+                    applied_at: Some(Utc::now()),
+                    ..ImageState::from(&new_image)
+                },
+            );
+            continue;
+        };
+
+        state.bad_images.insert(new_image.id.clone());
+
+        let Some(old_image) = old_image else {
+            return Err(err
+                .context(format!(
+                    "Updated {name} failed and there is nothing to roll back to"
+                ))
+                .context(AppDown(name)));
+        };
+
+        elogPrint!(
+            "supervisor",
+            "Updated {name} failed with image {}, rolling back to {}: {err:?}",
+            new_image.id,
+            old_image.id
+        );
 
-    let new_time = image_info::image_creation_time(&app.image)
+        retag_image(&old_image.id, &app.image, shutdown.clone())
+            .await
+            .context(AppDown(name.clone()))?;
+        stack.restart(&name).await.context(AppDown(name.clone()))?;
+        state
+            .images
+            .insert(name.clone(), ImageState::from(&old_image));
+
+        rollback.get_or_insert(err.context(RolledBack {
+            app: name,
+            image: old_image.id,
+        }));
+    }
+
+    // Other apps are still updated, but a rollback means the update failed
+    match rollback {
+        Some(err) => Err(err),
+        None => Ok(()),
+    }
+}
+
+/// An updated app failed and was rolled back, so it's running its previous image again.
+#[derive(Debug, Display)]
+#[display(fmt = "Updated {} failed, rolled back to image {}", app, image)]
+struct RolledBack {
+    app: String,
+    image: String,
+}
+
+impl std::error::Error for RolledBack {}
+
+/// An update left an app stopped or on a failing image. Any other update failure leaves the
+/// apps running their current image.
+#[derive(Debug, Display)]
+#[display(fmt = "{} may not be running after the update", _0)]
+struct AppDown(String);
+
+impl std::error::Error for AppDown {}
+
+async fn retag_image(image_id: &str, image: &str, shutdown: Shutdown) -> anyhow::Result<()> {
+    let mut process = Process::new("update", runner::tag_image(image_id, image), shutdown)
+        .context("Starting tagging process")?;
+
+    if !process
+        .wait()
         .await
-        .context("Getting image creation time")?;
+        .context("Waiting for tagging process")?
+        .success()
+    {
+        bail!("Failed tagging {image_id} as {image}");
+    }
+
+    Ok(())
+}
+
+async fn run(
+    config: config::Config,
+    tz: Tz,
+    mut state: StateFile,
+    notifier: Notifier,
+    pinger: Pinger,
+    shutdown: Shutdown,
+) -> anyhow::Result<ExitStatus> {
+    let mut stack = Stack::new(&config, notifier.clone(), shutdown.clone())?;
 
-    if new_time.is_some() && new_time != old_time {
-        logPrint!("supervisor", "Image updated, restarting app");
-        app_process
-            .terminate_and_wait()
+    if let Some(restore) = &config.restore {
+        restore_if_needed(restore, &tz, &notifier, shutdown.clone())
             .await
-            .context("Terminating app")?;
+            .context("Restoring backup, not starting apps")?;
+        if shutdown.shutdown_started() {
+            bail!("Shutting down while restoring backup")
+        }
+    }
 
-        app_process =
-            Process::new("app", runner::run_app(app), shutdown).context("Starting app process")?;
-    } else {
-        logPrint!("supervisor", "Image not updated. Do nothing");
+    for backup in config.backups() {
+        if backup.init_if_missing == Some(true) {
+            init_repo_if_missing(backup, config.restore.as_ref(), shutdown.clone())
+                .await
+                .with_context(|| format!("Initializing repository of {}", backup.name()))?;
+        }
     }
 
-    Ok(app_process)
+    logPrint!("supervisor", "Scheduling in timezone {tz}");
+
+    let status = supervise(
+        &mut stack, config, tz, &mut state, &notifier, &pinger, shutdown,
+    )
+    .await;
+
+    // Stop whatever is still running, however supervising ended
+    stack.stop().await;
+    stack.remove_pod().await;
+    status
 }
 
-async fn run(config: config::Config, shutdown: Shutdown) -> anyhow::Result<ExitStatus> {
+async fn supervise(
+    stack: &mut Stack,
+    config: config::Config,
+    tz: Tz,
+    state: &mut StateFile,
+    notifier: &Notifier,
+    pinger: &Pinger,
+    shutdown: Shutdown,
+) -> anyhow::Result<ExitStatus> {
     let config::Config {
         backup,
-        app,
         update,
-        restore,
+        api,
+        ..
     } = config;
     let update = update.unwrap_or_default();
 
-    if let Some(restore) = &restore {
-        restore_if_needed(restore, shutdown.clone()).await?;
-        if shutdown.shutdown_started() {
-            bail!("Shutting down while restoring backup")
+    let snapshot = Rc::new(RefCell::new(Snapshot::default()));
+    let mut api = match &api {
+        Some(api) => {
+            let snapshot = snapshot.clone();
+            let apps = stack.status_view();
+            let update = update.clone();
+            let answer = move |query| answer(query, apps.get(), &snapshot.borrow(), &update, tz);
+            Some(api::serve(api, answer).await.context("Starting API")?)
+        }
+        None => None,
+    };
+
+    let mut last_update = state
+        .state
+        .update
+        .scheduled_from
+        .map(|t| t.with_timezone(&tz));
+
+    let mut targets: Vec<_> = backup
+        .unwrap_or_default()
+        .into_iter()
+        .map(|backup| BackupTarget::new(backup, &state.state, tz))
+        .collect();
+
+    for target in &mut targets {
+        if target.scheduled_from(BackupJob::Backup).is_some() {
+            continue;
         }
-    }
-
-    let tz = current_timezone();
-
-    let mut last_update = None;
-    let mut last_backup = None;
 
-    if let Some(backup) = &backup {
-        last_backup = restic::get_latest_snapshot_time(backup)
-            .await
-            .map(|s| s.with_timezone(&tz));
+        match restic::get_latest_snapshot_time(&target.config).await {
+            Ok(Some(time)) => {
+                target.schedule_from(BackupJob::Backup, time.with_timezone(&tz));
+                state
+                    .state
+                    .job(target.name(), BackupJob::Backup)
+                    .last_success
+                    .get_or_insert(time);
+            }
+            Ok(None) => {
+                logPrint!("supervisor", "No snapshots found for {}", target.name());
+            }
+            Err(err @ (ResticError::MissingBinary | ResticError::AuthFailure(_))) => {
+                return Err(err)
+                    .with_context(|| format!("Accessing repository of {}", target.name()));
+            }
+            Err(err) => {
+                elogPrint!(
+                    "supervisor",
+                    "Unable to get latest snapshot of {}, backing up as soon as possible: {err}",
+                    target.name()
+                );
+            }
+        }
     }
 
-    let mut process = Process::new("app", runner::run_app(&app), shutdown.clone())
-        .context("Starting app process")?;
+    stack.create_pod().await?;
+    stack.start().await?;
 
     while !shutdown.shutdown_started() {
+        *snapshot.borrow_mut() = Snapshot {
+            targets: targets.clone(),
+            state: state.state.clone(),
+            last_update,
+        };
+
         let now = Utc::now().with_timezone(&tz);
 
-        let next_backup = backup
-            .as_ref()
-            .and_then(|b| b.interval.next(last_backup, now))
-            .map(|d| {
-                logPrint!("supervisor", "Next backup time is in {d:?}");
-                Instant::now() + d
-            });
+        let mut scheduled = Vec::new();
+        for (index, target) in targets.iter().enumerate() {
+            for job in BackupJob::ALL {
+                if let Some(d) = target.next_run(job, now) {
+                    logPrint!(
+                        "supervisor",
+                        "Next {job} time for {} is in {d:?}",
+                        target.name()
+                    );
+                    scheduled.push((Instant::now() + d, index, job));
+                }
+            }
+        }
+
+        let next_job = scheduled.iter().map(|(at, ..)| *at).min();
 
         let next_update = update.interval.next(last_update, now).map(|d| {
             logPrint!("supervisor", "Next update time is in {d:?}");
             Instant::now() + d
         });
 
-        select! {
-            _ = sleep_until_or_forever(next_backup) => {
-                let backup = backup.as_ref().unwrap();
-                process = start_backup(backup, &app, shutdown.clone(), process).await.context("Running backup process")?;
-                last_backup = Some(Utc::now().with_timezone(&tz));
-            }
+        let (action, reply) = select! {
+            _ = sleep_until_or_forever(next_job) => {
+                let now = Instant::now();
+                let due = scheduled
+                    .into_iter()
+                    .filter(|(at, ..)| *at <= now)
+                    .map(|(_, index, job)| (index, job))
+                    .collect();
 
-            _ = sleep_until_or_forever(next_update) => {
-                process = start_update(&app, process, shutdown.clone()).await.context("Running update process")?;
-                last_update = Some(Utc::now().with_timezone(&tz));
+                (Action::Jobs(due), None)
             }
 
-            status = process.wait() => {
+            _ = sleep_until_or_forever(next_update) => (Action::Update, None),
+
+            request = api::next_request(&mut api) => match action_for(request.command, &targets, stack) {
+                Ok(action) => (action, Some(request.reply)),
+                Err(response) => {
+                    let _ = request.reply.send(response);
+                    continue;
+                }
+            },
+
+            (name, status) = stack.wait() => {
+                logPrint!("supervisor", "App {name} exited, stopping the rest");
                 return status
             }
-        }
+        };
+
+        let result = match &action {
+            Action::Jobs(due) => run_backup_jobs(
+                &mut targets,
+                due,
+                stack,
+                tz,
+                state,
+                Reporting { notifier, pinger },
+                shutdown.clone(),
+            )
+            .await
+            .context("Running backup jobs"),
+
+            Action::Update => {
+                let ping = pinger.start(update.ping_url.as_deref());
+                let started = Instant::now();
+                let result =
+                    start_update(&update, stack, &mut state.state, notifier, shutdown.clone())
+                        .await;
+
+                // Unless an app was left down the apps are fine, so wait for the next update as
+                // usual, like a backup that ran out of retries
+                let advances_schedule = match &result {
+                    Ok(()) => true,
+                    Err(err) => err.downcast_ref::<AppDown>().is_none(),
+                };
+                state
+                    .state
+                    .update
+                    .record(&result, advances_schedule, started.elapsed());
+                state.save();
+                ping.finish(&result);
+
+                if result.is_err() {
+                    notifier.notify(Notification::job(
+                        "update",
+                        "apps",
+                        started.elapsed(),
+                        &result,
+                    ));
+                }
+                if advances_schedule {
+                    last_update = Some(Utc::now().with_timezone(&tz));
+                }
+
+                result.context("Running update process")
+            }
+
+            Action::Restart(name) => {
+                logPrint!("supervisor", "Restarting {name} on request");
+                stack.restart(name).await
+            }
+        };
+
+        let Some(reply) = reply else {
+            // Failures of scheduled actions still stop the supervisor, except for updates that
+            // left the apps running, which are reported like failed backups
+            match result {
+                Err(err)
+                    if matches!(action, Action::Update)
+                        && err.downcast_ref::<AppDown>().is_none() =>
+                {
+                    elogPrint!("supervisor", "{err:?}");
+                }
+                result => result?,
+            }
+            continue;
+        };
+
+        // A failed request is reported to the client, it mustn't take the supervisor down
+        let response = match (&result, &action) {
+            (Err(err), _) => {
+                elogPrint!("supervisor", "Request failed: {err:?}");
+                Response::error(500, err)
+            }
+            (Ok(()), Action::Jobs(due)) => Response::ok(job_outcomes(&targets, due, state)),
+            (Ok(()), _) => Response::ok(json!({ "ok": true })),
+        };
+        let _ = reply.send(response);
     }
 
     bail!("Shutting down")
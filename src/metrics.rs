@@ -0,0 +1,217 @@
+use std::fmt::{Display, Write};
+
+use chrono::DateTime;
+use chrono_tz::Tz;
+
+use crate::{
+    stack::AppStatus,
+    state::State,
+    targets::{BackupJob, BackupTarget},
+};
+
+/// Render the supervisor's state in the Prometheus text exposition format.
+pub fn render(
+    apps: &[AppStatus],
+    targets: &[BackupTarget],
+    state: &State,
+    now: DateTime<Tz>,
+) -> String {
+    let mut out = Metrics::default();
+
+    out.family(
+        "pdrun_app_up",
+        "gauge",
+        "Whether the app's container is running",
+    );
+    for app in apps {
+        out.sample("app", &app.name, u8::from(app.running));
+    }
+
+    out.family(
+        "pdrun_app_restarts_total",
+        "counter",
+        "Restarts after the app crashed or became unhealthy",
+    );
+    for app in apps {
+        out.sample("app", &app.name, app.restarts);
+    }
+
+    out.family(
+        "pdrun_update_last_applied_timestamp",
+        "gauge",
+        "When an update last deployed a new image for the app, in seconds since the epoch",
+    );
+    for app in apps {
+        if let Some(at) = state.images.get(&app.name).and_then(|i| i.applied_at) {
+            out.sample("app", &app.name, at.timestamp());
+        }
+    }
+
+    let backups = |target: &BackupTarget| {
+        state
+            .targets
+            .get(target.name())
+            .and_then(|jobs| jobs.get(&BackupJob::Backup))
+    };
+
+    out.family(
+        "pdrun_backup_last_success_timestamp",
+        "gauge",
+        "When the target was last backed up successfully, in seconds since the epoch",
+    );
+    for target in targets {
+        if let Some(at) = backups(target).and_then(|s| s.last_success) {
+            out.sample("target", target.name(), at.timestamp());
+        }
+    }
+
+    out.family(
+        "pdrun_backup_duration_seconds",
+        "gauge",
+        "How long the target's last backup attempt took",
+    );
+    for target in targets {
+        if let Some(duration) = backups(target).and_then(|s| s.last_duration) {
+            out.sample("target", target.name(), duration);
+        }
+    }
+
+    out.family(
+        "pdrun_backup_bytes_added",
+        "gauge",
+        "Bytes the target's last successful backup added to the repository",
+    );
+    for target in targets {
+        if let Some(summary) = state.backup_summaries.get(target.name()) {
+            out.sample("target", target.name(), summary.data_added);
+        }
+    }
+
+    out.family(
+        "pdrun_next_backup_seconds",
+        "gauge",
+        "Seconds until the target's next scheduled backup",
+    );
+    for target in targets {
+        if let Some(next) = target.next_run(BackupJob::Backup, now) {
+            out.sample("target", target.name(), next.as_secs_f64());
+        }
+    }
+
+    out.out
+}
+
+#[derive(Default)]
+struct Metrics {
+    out: String,
+    family: &'static str,
+}
+
+impl Metrics {
+    fn family(&mut self, name: &'static str, kind: &str, help: &str) {
+        self.family = name;
+        let _ = writeln!(self.out, "# HELP {name} {help}");
+        let _ = writeln!(self.out, "# TYPE {name} {kind}");
+    }
+
+    /// A sample of the family started last, with a single label.
+    fn sample(&mut self, label: &str, value: &str, sample: impl Display) {
+        let _ = writeln!(
+            self.out,
+            "{}{{{label}=\"{}\"}} {sample}",
+            self.family,
+            escape(value)
+        );
+    }
+}
+
+/// Label values are quoted, so backslashes, quotes and newlines have to be escaped.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use super::*;
+    use crate::{backup::BackupSummary, config::BackupConfig, state::ImageState};
+
+    fn app(name: &str, running: bool, restarts: u64) -> AppStatus {
+        AppStatus {
+            name: name.to_string(),
+            container_name: format!("pdrun-{name}"),
+            image: "nginx".to_string(),
+            running,
+            uptime: None,
+            restarts,
+        }
+    }
+
+    #[test]
+    fn renders_apps_and_backups() {
+        let at = Utc.with_ymd_and_hms(2026, 5, 1, 3, 0, 0).unwrap();
+
+        let mut state = State::default();
+        state.images.insert(
+            "web".to_string(),
+            ImageState {
+                id: "abc".to_string(),
+                digest: "sha256:abc".to_string(),
+                applied_at: Some(at),
+            },
+        );
+        let job = state.job("db", BackupJob::Backup);
+        job.scheduled_from = Some(at);
+        job.last_success = Some(at);
+        job.last_duration = Some(2.5);
+        state.backup_summaries.insert(
+            "db".to_string(),
+            BackupSummary {
+                snapshot_id: None,
+                files_new: 1,
+                files_changed: 0,
+                data_added: 4096,
+                total_bytes_processed: 8192,
+                total_duration: 2.5,
+            },
+        );
+
+        let config: BackupConfig =
+            serde_yaml::from_str("name: db\nrepo: /repo\nsrc: /data\ninterval: daily").unwrap();
+        let targets = [BackupTarget::new(config, &state, Tz::UTC)];
+        let apps = [app("web", true, 2), app("db", false, 0)];
+        let now = (at + chrono::Duration::hours(1)).with_timezone(&Tz::UTC);
+
+        let metrics = render(&apps, &targets, &state, now);
+
+        for line in [
+            "# TYPE pdrun_app_up gauge",
+            "pdrun_app_up{app=\"web\"} 1",
+            "pdrun_app_up{app=\"db\"} 0",
+            "# TYPE pdrun_app_restarts_total counter",
+            "pdrun_app_restarts_total{app=\"web\"} 2",
+            "pdrun_update_last_applied_timestamp{app=\"web\"} 1777604400",
+            "pdrun_backup_last_success_timestamp{target=\"db\"} 1777604400",
+            "pdrun_backup_duration_seconds{target=\"db\"} 2.5",
+            "pdrun_backup_bytes_added{target=\"db\"} 4096",
+            "pdrun_next_backup_seconds{target=\"db\"} 82800",
+        ] {
+            assert!(
+                metrics.lines().any(|l| l == line),
+                "{line} missing from\n{metrics}"
+            );
+        }
+        assert!(!metrics.contains("pdrun_update_last_applied_timestamp{app=\"db\"}"));
+    }
+
+    #[test]
+    fn escapes_label_values() {
+        assert_eq!(escape(r#"a\b"c"#), r#"a\\b\"c"#);
+        assert_eq!(escape("a\nb"), r"a\nb");
+        assert_eq!(escape("plain-name_1"), "plain-name_1");
+    }
+}
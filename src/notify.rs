@@ -0,0 +1,414 @@
+use std::{cell::RefCell, fmt::Display, process::ExitStatus, rc::Rc, time::Duration};
+
+use anyhow::Context;
+use async_shutdown::Shutdown;
+use serde::Serialize;
+use serde_json::json;
+use tokio::{
+    process::Command,
+    task::{spawn_local, JoinHandle},
+};
+
+use crate::{
+    config::{NotificationConfig, NotificationEvent, NotificationFormat},
+    log::{elogPrint, redact},
+    process::run_to_end,
+};
+
+const DEFAULT_EVENTS: &[NotificationEvent] =
+    &[NotificationEvent::Failure, NotificationEvent::AppCrashed];
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Errors are cut down to this many characters, webhook targets tend to limit message sizes
+const MAX_ERROR_LEN: usize = 1000;
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Notification {
+    pub event: NotificationEvent,
+    /// `backup`, `restore`, `update`, `check`, ... or `app` for app crashes
+    pub job: String,
+    /// The app, backup target or restore destination the job was for
+    pub target: String,
+    pub status: Status,
+    /// In seconds
+    pub duration: Option<f64>,
+    pub error: Option<String>,
+    /// The image an update deployed
+    pub image: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Success,
+    Failure,
+}
+
+impl Notification {
+    /// The outcome of a job that took `took`.
+    pub fn job(
+        job: impl Display,
+        target: &str,
+        took: Duration,
+        result: &anyhow::Result<()>,
+    ) -> Self {
+        let (event, status) = match result {
+            Ok(()) => (NotificationEvent::Success, Status::Success),
+            Err(_) => (NotificationEvent::Failure, Status::Failure),
+        };
+
+        Self {
+            event,
+            job: job.to_string(),
+            target: target.to_string(),
+            status,
+            duration: Some(took.as_secs_f64()),
+            error: result.as_ref().err().map(excerpt),
+            image: None,
+        }
+    }
+
+    pub fn update_applied(app: &str, image: &str) -> Self {
+        Self {
+            event: NotificationEvent::UpdateApplied,
+            job: "update".to_string(),
+            target: app.to_string(),
+            status: Status::Success,
+            duration: None,
+            error: None,
+            image: Some(image.to_string()),
+        }
+    }
+
+    pub fn app_crashed(app: &str, status: &anyhow::Result<ExitStatus>) -> Self {
+        let error = match status {
+            Ok(status) => format!("Exited with {status}"),
+            Err(err) => excerpt(err),
+        };
+
+        Self {
+            event: NotificationEvent::AppCrashed,
+            job: "app".to_string(),
+            target: app.to_string(),
+            status: Status::Failure,
+            duration: None,
+            error: Some(error),
+            image: None,
+        }
+    }
+
+    fn title(&self) -> String {
+        let outcome = match self.event {
+            NotificationEvent::Failure => "failed",
+            NotificationEvent::Success => "succeeded",
+            NotificationEvent::UpdateApplied => "applied",
+            NotificationEvent::AppCrashed => "crashed",
+        };
+
+        format!("pdrun: {} of {} {outcome}", self.job, self.target)
+    }
+
+    fn message(&self) -> String {
+        let mut message = self.title();
+
+        if let Some(duration) = self.duration {
+            message.push_str(&format!(" after {duration:.0}s"));
+        }
+
+        if let Some(image) = &self.image {
+            message.push_str(&format!(", now running {image}"));
+        }
+
+        if let Some(error) = &self.error {
+            message.push_str(&format!(": {error}"));
+        }
+
+        message
+    }
+}
+
+fn excerpt(err: &anyhow::Error) -> String {
+    redact(format!("{err:#}"))
+        .chars()
+        .take(MAX_ERROR_LEN)
+        .collect()
+}
+
+/// Sends notifications to the configured channels. Cheap to clone, clones share pending sends.
+#[derive(Clone)]
+pub struct Notifier {
+    channels: Rc<Vec<NotificationConfig>>,
+    client: reqwest::Client,
+    pending: Rc<RefCell<Vec<JoinHandle<()>>>>,
+}
+
+impl Notifier {
+    pub fn new(channels: Vec<NotificationConfig>) -> anyhow::Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .context("Building HTTP client")?;
+
+        Ok(Self {
+            channels: Rc::new(channels),
+            client,
+            pending: Rc::default(),
+        })
+    }
+
+    /// Send to every channel that wants this event. Sending happens in the background, so a
+    /// slow endpoint can't hold up supervising.
+    pub fn notify(&self, notification: Notification) {
+        let mut pending = self.pending.borrow_mut();
+        pending.retain(|handle| !handle.is_finished());
+
+        for channel in self.channels.iter() {
+            let events = channel.events.as_deref().unwrap_or(DEFAULT_EVENTS);
+            if !events.contains(&notification.event) {
+                continue;
+            }
+
+            let channel = channel.clone();
+            let client = self.client.clone();
+            let notification = notification.clone();
+
+            pending.push(spawn_local(async move {
+                if let Err(err) = send(&client, &channel, &notification).await {
+                    elogPrint!("supervisor", "Unable to send notification: {err:?}");
+                }
+            }));
+        }
+    }
+
+    /// Wait for notifications still being sent, e.g. before exiting.
+    pub async fn flush(&self) {
+        let pending: Vec<_> = self.pending.borrow_mut().drain(..).collect();
+        for handle in pending {
+            let _ = handle.await;
+        }
+    }
+
+    /// Send a test notification to every channel regardless of its filter, failing if any
+    /// of them can't be reached.
+    pub async fn test(&self) -> anyhow::Result<()> {
+        let notification = Notification {
+            event: NotificationEvent::Success,
+            job: "test".to_string(),
+            target: "pdrun".to_string(),
+            status: Status::Success,
+            duration: None,
+            error: None,
+            image: None,
+        };
+
+        for channel in self.channels.iter() {
+            send(&self.client, channel, &notification).await?;
+        }
+
+        Ok(())
+    }
+}
+
+async fn send(
+    client: &reqwest::Client,
+    channel: &NotificationConfig,
+    notification: &Notification,
+) -> anyhow::Result<()> {
+    if let Some(command) = &channel.command {
+        let mut cmd = Command::new(&command[0]);
+        cmd.args(&command[1..])
+            .env("PDRUN_EVENT", notification.event.to_string())
+            .env("PDRUN_JOB", &notification.job)
+            .env("PDRUN_TARGET", &notification.target)
+            .env("PDRUN_MESSAGE", notification.message())
+            .env(
+                "PDRUN_ERROR",
+                notification.error.as_deref().unwrap_or_default(),
+            )
+            .env("PDRUN_NOTIFICATION", serde_json::to_string(notification)?);
+
+        // Notifications about shutting down still have to go out
+        return run_to_end("notify", cmd, Shutdown::new()).await;
+    }
+
+    let url = channel
+        .webhook
+        .as_deref()
+        .context("Notification has no target")?;
+    let failed = notification.status == Status::Failure;
+
+    let request = match channel.format.unwrap_or_default() {
+        NotificationFormat::Json => client.post(url).json(notification),
+        NotificationFormat::Ntfy => client
+            .post(url)
+            .header("Title", notification.title())
+            .header("Priority", if failed { "high" } else { "default" })
+            .header(
+                "Tags",
+                if failed {
+                    "warning"
+                } else {
+                    "white_check_mark"
+                },
+            )
+            .body(notification.message()),
+        NotificationFormat::Gotify => client.post(url).json(&json!({
+            "title": notification.title(),
+            "message": notification.message(),
+            "priority": if failed { 8 } else { 4 },
+        })),
+        NotificationFormat::Slack => client.post(url).json(&json!({
+            "text": notification.message(),
+        })),
+    };
+
+    request
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .with_context(|| format!("Sending notification to {}", redact(url.to_string())))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    fn failure() -> Notification {
+        Notification {
+            event: NotificationEvent::Failure,
+            job: "backup".to_string(),
+            target: "db".to_string(),
+            status: Status::Failure,
+            duration: Some(12.0),
+            error: Some("restic exited with 1".to_string()),
+            image: None,
+        }
+    }
+
+    fn channel(url: String, format: NotificationFormat) -> NotificationConfig {
+        NotificationConfig {
+            webhook: Some(url),
+            format: Some(format),
+            command: None,
+            events: None,
+        }
+    }
+
+    /// Send `notification` to a stand-in webhook, returning the request's headers and body.
+    async fn deliver(
+        format: NotificationFormat,
+        notification: &Notification,
+    ) -> (Vec<String>, String) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+
+        let receive = async {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = BufReader::new(stream);
+
+            let mut headers = Vec::new();
+            loop {
+                let mut line = String::new();
+                stream.read_line(&mut line).await.unwrap();
+                let line = line.trim_end().to_string();
+                if line.is_empty() {
+                    break;
+                }
+                headers.push(line);
+            }
+
+            let length = headers
+                .iter()
+                .find_map(|h| {
+                    h.to_lowercase()
+                        .strip_prefix("content-length: ")?
+                        .parse()
+                        .ok()
+                })
+                .unwrap_or(0);
+            let mut body = vec![0; length];
+            stream.read_exact(&mut body).await.unwrap();
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+                .await
+                .unwrap();
+
+            (headers, String::from_utf8(body).unwrap())
+        };
+
+        let client = reqwest::Client::new();
+        let channel = channel(url, format);
+        let (sent, received) = tokio::join!(send(&client, &channel, notification), receive);
+        sent.unwrap();
+        received
+    }
+
+    #[tokio::test]
+    async fn json_posts_the_notification() {
+        let (headers, body) = deliver(NotificationFormat::Json, &failure()).await;
+
+        assert_eq!(headers[0], "POST /hook HTTP/1.1");
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(
+            body,
+            json!({
+                "event": "failure",
+                "job": "backup",
+                "target": "db",
+                "status": "failure",
+                "duration": 12.0,
+                "error": "restic exited with 1",
+                "image": null,
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn ntfy_posts_the_message_with_headers() {
+        let (headers, body) = deliver(NotificationFormat::Ntfy, &failure()).await;
+
+        let headers: Vec<_> = headers.iter().map(|h| h.to_lowercase()).collect();
+        assert!(headers.contains(&"title: pdrun: backup of db failed".to_string()));
+        assert!(headers.contains(&"priority: high".to_string()));
+        assert!(headers.contains(&"tags: warning".to_string()));
+        assert_eq!(
+            body,
+            "pdrun: backup of db failed after 12s: restic exited with 1"
+        );
+    }
+
+    #[tokio::test]
+    async fn gotify_posts_title_message_and_priority() {
+        let (_, body) = deliver(NotificationFormat::Gotify, &failure()).await;
+
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(
+            body,
+            json!({
+                "title": "pdrun: backup of db failed",
+                "message": "pdrun: backup of db failed after 12s: restic exited with 1",
+                "priority": 8,
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn slack_posts_the_message_as_text() {
+        let notification = Notification::update_applied("web", "nginx:1.27");
+        let (_, body) = deliver(NotificationFormat::Slack, &notification).await;
+
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(
+            body,
+            json!({ "text": "pdrun: update of web applied, now running nginx:1.27" })
+        );
+    }
+}
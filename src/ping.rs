@@ -0,0 +1,123 @@
+use std::{cell::RefCell, rc::Rc, time::Duration};
+
+use anyhow::Context;
+use tokio::task::{spawn_local, JoinHandle};
+
+use crate::{
+    log::{elogPrint, redact},
+    process::ProcessFailed,
+};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Sends pings to healthchecks.io style monitors. Cheap to clone, clones share pending sends.
+#[derive(Clone)]
+pub struct Pinger {
+    client: reqwest::Client,
+    pending: Rc<RefCell<Vec<JoinHandle<()>>>>,
+}
+
+impl Pinger {
+    pub fn new() -> anyhow::Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .context("Building HTTP client")?;
+
+        Ok(Self {
+            client,
+            pending: Rc::default(),
+        })
+    }
+
+    /// Tell the monitor at `url` that a job has started, so it can also catch jobs that hang.
+    pub fn start(&self, url: Option<&str>) -> Ping {
+        let start = url.map(|url| {
+            spawn_local(send(
+                self.client.clone(),
+                format!("{}/start", url.trim_end_matches('/')),
+                String::new(),
+            ))
+        });
+
+        Ping {
+            pinger: self.clone(),
+            url: url.map(str::to_string),
+            start,
+        }
+    }
+
+    /// Wait for pings still being sent, e.g. before exiting.
+    pub async fn flush(&self) {
+        let pending: Vec<_> = self.pending.borrow_mut().drain(..).collect();
+        for handle in pending {
+            let _ = handle.await;
+        }
+    }
+}
+
+/// The pings of one job run. Like notifications they're sent in the background, so a slow
+/// monitor can't hold up the job.
+pub struct Ping {
+    pinger: Pinger,
+    url: Option<String>,
+    start: Option<JoinHandle<()>>,
+}
+
+impl Ping {
+    /// Report how the job went: the base URL on success, `/fail` with the error and the tail
+    /// of the job's output on failure. Sent after the start ping, so the monitor sees them in
+    /// order.
+    pub fn finish(self, result: &anyhow::Result<()>) {
+        let Some(url) = self.url else {
+            return;
+        };
+
+        let (url, body) = match result {
+            Ok(()) => (url, String::new()),
+            Err(err) => {
+                let mut body = format!("{err:#}");
+
+                let output = err.chain().find_map(|e| e.downcast_ref::<ProcessFailed>());
+                if let Some(output) = output {
+                    body.push_str("\n\n");
+                    body.push_str(&output.output_tail.join("\n"));
+                }
+
+                (format!("{}/fail", url.trim_end_matches('/')), redact(body))
+            }
+        };
+
+        let start = self.start;
+        let client = self.pinger.client.clone();
+        let handle = spawn_local(async move {
+            if let Some(start) = start {
+                let _ = start.await;
+            }
+            send(client, url, body).await;
+        });
+
+        let mut pending = self.pinger.pending.borrow_mut();
+        pending.retain(|handle| !handle.is_finished());
+        pending.push(handle);
+    }
+}
+
+/// Pings are best effort, a monitor being unreachable mustn't affect the job.
+async fn send(client: reqwest::Client, url: String, body: String) {
+    let result = async {
+        client
+            .post(&url)
+            .body(body)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())?;
+        anyhow::Ok(())
+    }
+    .await
+    .with_context(|| format!("Pinging {}", redact(url.clone())));
+
+    if let Err(err) = result {
+        elogPrint!("supervisor", "{err:?}");
+    }
+}
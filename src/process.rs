@@ -1,10 +1,15 @@
 use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    fmt::{self, Display},
     process::{ExitStatus, Stdio},
+    rc::Rc,
     time::Duration,
 };
 
 use anyhow::{anyhow, Context};
 use async_shutdown::Shutdown;
+use futures::future::join;
 use nix::{
     sys::signal::{kill, Signal::SIGTERM},
     unistd::Pid,
@@ -19,11 +24,35 @@ use tokio::{
 
 use crate::log::{elogPrint, logPrint};
 
+/// Lines of output kept around for reporting why a process failed
+const OUTPUT_TAIL_LINES: usize = 20;
+
+const OUTPUT_DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+type OutputTail = Rc<RefCell<VecDeque<String>>>;
+
 pub struct Process {
     shutdown: Shutdown,
     exit_watcher: watch::Receiver<Option<anyhow::Result<ExitStatus>>>,
+    output_tail: OutputTail,
+}
+
+/// A child process that exited unsuccessfully, along with the last lines it printed.
+#[derive(Debug)]
+pub struct ProcessFailed {
+    pub log_prefix: String,
+    pub status: ExitStatus,
+    pub output_tail: Vec<String>,
+}
+
+impl Display for ProcessFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} process exited with {}", self.log_prefix, self.status)
+    }
 }
 
+impl std::error::Error for ProcessFailed {}
+
 impl Process {
     pub fn new(
         log_prefix: impl AsRef<str>,
@@ -63,8 +92,17 @@ impl Process {
 
         let (exit_sender, exit_watcher) = watch::channel(None);
 
-        spawn_local(redirect_output(log_prefix.clone(), stdout));
-        spawn_local(redirect_error(log_prefix.clone(), stderr));
+        let output_tail = OutputTail::default();
+        let output = spawn_local(redirect_output(
+            log_prefix.clone(),
+            stdout,
+            output_tail.clone(),
+        ));
+        let error = spawn_local(redirect_error(
+            log_prefix.clone(),
+            stderr,
+            output_tail.clone(),
+        ));
 
         let internal_shutdown = Shutdown::new();
 
@@ -80,6 +118,10 @@ impl Process {
                 )
                 .await;
 
+                // Let the last lines of output through before reporting the exit, but don't
+                // wait forever on a grandchild that inherited the pipes
+                let _ = timeout(OUTPUT_DRAIN_TIMEOUT, join(output, error)).await;
+
                 match &status {
                     Ok(status) if status.success() => {
                         logPrint!(
@@ -110,6 +152,7 @@ impl Process {
         Ok(Self {
             shutdown: internal_shutdown,
             exit_watcher,
+            output_tail,
         })
     }
 
@@ -120,7 +163,7 @@ impl Process {
             .context("Waiting for status")?;
 
         match self.exit_watcher.borrow().as_ref() {
-            Some(Ok(status)) => Ok(status.clone()),
+            Some(Ok(status)) => Ok(*status),
             Some(Err(err)) => Err(anyhow!("Child process exited with error: {err:?}")),
             None => panic!("Must have result"),
         }
@@ -130,6 +173,44 @@ impl Process {
         self.shutdown.shutdown();
         self.wait().await
     }
+
+    /// The last lines the process wrote to stdout or stderr.
+    pub fn output_tail(&self) -> Vec<String> {
+        self.output_tail.borrow().iter().cloned().collect()
+    }
+}
+
+/// Run a command as a child process, failing unless it exits successfully.
+pub async fn run_to_end(log_prefix: &str, cmd: Command, shutdown: Shutdown) -> anyhow::Result<()> {
+    run_to_end_with_output(log_prefix, cmd, shutdown)
+        .await
+        .map(|_| ())
+}
+
+/// Like `run_to_end`, returning the last lines the process printed.
+pub async fn run_to_end_with_output(
+    log_prefix: &str,
+    cmd: Command,
+    shutdown: Shutdown,
+) -> anyhow::Result<Vec<String>> {
+    let mut process = Process::new(log_prefix, cmd, shutdown)
+        .with_context(|| format!("Starting {log_prefix} process"))?;
+
+    let status = process
+        .wait()
+        .await
+        .with_context(|| format!("Waiting for {log_prefix} process"))?;
+
+    if !status.success() {
+        return Err(ProcessFailed {
+            log_prefix: log_prefix.to_string(),
+            status,
+            output_tail: process.output_tail(),
+        }
+        .into());
+    }
+
+    Ok(process.output_tail())
 }
 
 async fn monitor_exit_status(
@@ -151,7 +232,7 @@ async fn monitor_exit_status(
     }
 
     match timeout(Duration::from_secs(5), child.wait()).await {
-        Ok(status) => return status.context("Getting exit status"),
+        Ok(status) => status.context("Getting exit status"),
         Err(_) => {
             logPrint!(
                 "supervisor",
@@ -167,24 +248,42 @@ async fn monitor_exit_status(
     }
 }
 
-async fn redirect_output(log_prefix: String, from: impl AsyncRead + Unpin) -> anyhow::Result<()> {
+async fn redirect_output(
+    log_prefix: String,
+    from: impl AsyncRead + Unpin,
+    tail: OutputTail,
+) -> anyhow::Result<()> {
     let mut from = BufReader::new(from);
     let mut line = String::default();
     while from.read_line(&mut line).await.context("Read line")? > 0 {
         logPrint!(&log_prefix, "{}", line.trim_end());
+        keep_line(&tail, &line);
         line.clear();
     }
 
     Ok(())
 }
 
-async fn redirect_error(log_prefix: String, from: impl AsyncRead + Unpin) -> anyhow::Result<()> {
+async fn redirect_error(
+    log_prefix: String,
+    from: impl AsyncRead + Unpin,
+    tail: OutputTail,
+) -> anyhow::Result<()> {
     let mut from = BufReader::new(from);
     let mut line = String::default();
     while from.read_line(&mut line).await.context("Read line")? > 0 {
         elogPrint!(&log_prefix, "{}", line.trim_end());
+        keep_line(&tail, &line);
         line.clear();
     }
 
     Ok(())
 }
+
+fn keep_line(tail: &OutputTail, line: &str) {
+    let mut tail = tail.borrow_mut();
+    if tail.len() == OUTPUT_TAIL_LINES {
+        tail.pop_front();
+    }
+    tail.push_back(line.trim_end().to_string());
+}
@@ -1,17 +1,27 @@
-use std::{collections::HashMap, process::Stdio};
+use std::{
+    collections::HashMap,
+    io::ErrorKind,
+    process::{ExitStatus, Stdio},
+    time::Duration,
+};
 
 use chrono::{DateTime, Utc};
-use serde::Deserialize;
-use tokio::process::Command;
+use derive_more::Display;
+use serde::{de::DeserializeOwned, Deserialize};
+use tokio::{process::Command, time::sleep};
 
 use crate::{
     config::{BackupConfig, RestoreConfig},
     log::logPrint,
 };
 
+const LOCK_RETRIES: u32 = 3;
+const LOCK_RETRY_DELAY: Duration = Duration::from_secs(10);
+
 #[derive(Debug, Clone, Deserialize)]
-struct Snapshot {
-    time: DateTime<Utc>,
+pub struct Snapshot {
+    pub id: String,
+    pub time: DateTime<Utc>,
 }
 
 pub trait ResticConfig {
@@ -35,7 +45,7 @@ impl ResticConfig for RestoreConfig {
     }
 
     fn repo(&self) -> &str {
-        &self.repo
+        self.repo.as_deref().unwrap_or_default()
     }
 }
 
@@ -52,30 +62,224 @@ pub fn build_restic_command(config: &impl ResticConfig) -> Command {
     cmd
 }
 
-pub async fn get_latest_snapshot_time(config: &BackupConfig) -> Option<DateTime<Utc>> {
+/// Narrow a snapshot listing down to the ones made by this backup target.
+pub fn add_snapshot_filter(cmd: &mut Command, backup: &BackupConfig) {
+    for path in &backup.src {
+        cmd.arg("--path").arg(path);
+    }
+
+    if let Some(host) = &backup.host {
+        cmd.arg("--host").arg(host);
+    }
+
+    if let Some(tags) = &backup.tags {
+        if !tags.is_empty() {
+            cmd.arg("--tag").arg(tags.join(","));
+        }
+    }
+}
+
+/// Why a restic command failed, so callers can tell a missing repo from a broken one.
+#[derive(Debug, Display)]
+pub enum ResticError {
+    #[display(fmt = "restic binary not found in PATH")]
+    MissingBinary,
+    #[display(fmt = "Repository is not initialized: {}", _0)]
+    RepoNotInitialized(String),
+    #[display(
+        fmt = "Unable to open repository, wrong password or credentials: {}",
+        _0
+    )]
+    AuthFailure(String),
+    #[display(fmt = "Repository is locked by another process: {}", _0)]
+    LockHeld(String),
+    #[display(fmt = "Unable to parse restic output: {}", _0)]
+    Parse(serde_json::Error),
+    #[display(fmt = "Unable to run restic: {}", _0)]
+    Io(std::io::Error),
+    #[display(fmt = "restic exited with {}: {}", _0, _1)]
+    Failed(ExitStatus, String),
+}
+
+impl std::error::Error for ResticError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Parse(err) => Some(err),
+            Self::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl ResticError {
+    /// Classify a failed run by its exit code, falling back to the message for restic
+    /// versions that predate the dedicated exit codes.
+    fn from_output(status: ExitStatus, stderr: &[u8]) -> Self {
+        let stderr = String::from_utf8_lossy(stderr).trim().to_string();
+
+        match status.code() {
+            Some(10) => return Self::RepoNotInitialized(stderr),
+            Some(11) => return Self::LockHeld(stderr),
+            Some(12) => return Self::AuthFailure(stderr),
+            _ => {}
+        }
+
+        if stderr.contains("Is there a repository at the following location?")
+            || stderr.contains("repository does not exist")
+        {
+            Self::RepoNotInitialized(stderr)
+        } else if stderr.contains("wrong password or no key found") {
+            Self::AuthFailure(stderr)
+        } else if stderr.contains("repository is already locked") {
+            Self::LockHeld(stderr)
+        } else {
+            Self::Failed(status, stderr)
+        }
+    }
+}
+
+/// Run a restic command to completion and return what it printed.
+async fn run_output(mut cmd: Command) -> Result<Vec<u8>, ResticError> {
+    let output = cmd
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .output()
+        .await
+        .map_err(|err| match err.kind() {
+            ErrorKind::NotFound => ResticError::MissingBinary,
+            _ => ResticError::Io(err),
+        })?;
+
+    if !output.status.success() {
+        return Err(ResticError::from_output(output.status, &output.stderr));
+    }
+
+    Ok(output.stdout)
+}
+
+/// Run a restic command that prints JSON and parse its output.
+async fn run_json<T: DeserializeOwned>(cmd: Command) -> Result<T, ResticError> {
+    serde_json::from_slice(&run_output(cmd).await?).map_err(ResticError::Parse)
+}
+
+/// Whether the repository has been initialized, judged by whether its config can be read.
+pub async fn repo_exists(config: &impl ResticConfig) -> Result<bool, ResticError> {
+    let mut cmd = build_restic_command(config);
+    cmd.args(["cat", "config"]);
+
+    match run_output(cmd).await {
+        Ok(_) => Ok(true),
+        Err(ResticError::RepoNotInitialized(_)) => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+pub fn init(config: &impl ResticConfig) -> Command {
     let mut cmd = build_restic_command(config);
+    cmd.arg("init");
+    cmd
+}
 
+/// Time of the latest snapshot made by this backup target, retrying for a while if the
+/// repository is locked.
+pub async fn get_latest_snapshot_time(
+    config: &BackupConfig,
+) -> Result<Option<DateTime<Utc>>, ResticError> {
     logPrint!(
         "supervisor",
         "Getting latest snapshot time on {}",
         config.repo
     );
 
-    let snapshots: Vec<Snapshot> = serde_json::from_slice(
-        &cmd.args(["snapshots", "--json", "--latest", "1"])
-            .arg("--path")
-            .arg(&config.src)
-            .stdout(Stdio::piped())
-            .stderr(Stdio::inherit())
-            .kill_on_drop(true)
-            .spawn()
-            .ok()?
-            .wait_with_output()
-            .await
-            .ok()?
-            .stdout,
-    )
-    .ok()?;
-
-    snapshots.into_iter().next().map(|s| s.time)
+    let mut attempt = 0;
+    loop {
+        let mut cmd = build_restic_command(config);
+        cmd.args(["snapshots", "--json", "--latest", "1"]);
+        add_snapshot_filter(&mut cmd, config);
+
+        match run_json::<Vec<Snapshot>>(cmd).await {
+            Ok(snapshots) => return Ok(snapshots.into_iter().map(|s| s.time).max()),
+            Err(ResticError::LockHeld(_)) if attempt < LOCK_RETRIES => {
+                attempt += 1;
+                logPrint!(
+                    "supervisor",
+                    "Repository {} is locked, retrying in {LOCK_RETRY_DELAY:?} ({attempt}/{LOCK_RETRIES})",
+                    config.repo
+                );
+                sleep(LOCK_RETRY_DELAY).await;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Run a `restic snapshots --json` command and parse its output.
+pub async fn list_snapshots(cmd: Command) -> Result<Vec<Snapshot>, ResticError> {
+    run_json(cmd).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::process::ExitStatusExt;
+
+    use super::*;
+
+    fn classify(code: i32, stderr: &str) -> ResticError {
+        ResticError::from_output(ExitStatus::from_raw(code << 8), stderr.as_bytes())
+    }
+
+    #[test]
+    fn exit_codes_take_precedence() {
+        assert!(matches!(
+            classify(10, "Fatal: repository does not exist"),
+            ResticError::RepoNotInitialized(_)
+        ));
+        assert!(matches!(
+            classify(11, "Fatal: unable to create lock"),
+            ResticError::LockHeld(_)
+        ));
+        assert!(matches!(
+            classify(12, "Fatal: wrong password or no key found\n"),
+            ResticError::AuthFailure(message) if message == "Fatal: wrong password or no key found"
+        ));
+    }
+
+    #[test]
+    fn older_versions_are_classified_by_message() {
+        let missing = "Fatal: unable to open config file: stat /repo/config: no such file or \
+                       directory\nIs there a repository at the following location?\n/repo";
+        assert!(matches!(
+            classify(1, missing),
+            ResticError::RepoNotInitialized(_)
+        ));
+        assert!(matches!(
+            classify(
+                1,
+                "Fatal: create repository at s3:bucket failed: repository does not exist"
+            ),
+            ResticError::RepoNotInitialized(_)
+        ));
+        assert!(matches!(
+            classify(1, "Fatal: wrong password or no key found"),
+            ResticError::AuthFailure(_)
+        ));
+        assert!(matches!(
+            classify(
+                1,
+                "unable to create lock in backend: repository is already locked by PID 42"
+            ),
+            ResticError::LockHeld(_)
+        ));
+    }
+
+    #[test]
+    fn anything_else_is_a_plain_failure() {
+        let err = classify(3, "Warning: at least one source file could not be read");
+        assert!(matches!(&err, ResticError::Failed(status, _) if status.code() == Some(3)));
+        assert_eq!(
+            err.to_string(),
+            "restic exited with exit status: 3: Warning: at least one source file could not be read"
+        );
+    }
 }
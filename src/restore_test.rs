@@ -0,0 +1,176 @@
+use std::{
+    fs,
+    path::{Component, Path, PathBuf},
+    process,
+};
+
+use anyhow::{bail, Context};
+use async_shutdown::Shutdown;
+use tokio::{process::Command, task::spawn_blocking};
+
+use crate::{
+    config::{BackupConfig, RestoreTestConfig},
+    log::logPrint,
+    process::run_to_end,
+    restic::{add_snapshot_filter, build_restic_command},
+    restores::remove_path,
+};
+
+const DEFAULT_MAX_DIFFERENCE: f64 = 10.0;
+
+/// Restore the latest snapshot of the target into a scratch directory and check it looks like
+/// the live data, either with the configured command or by comparing file counts and sizes.
+/// Comparing is only done for targets without excludes, see `BackupConfig::validate`.
+pub async fn run(
+    backup: &BackupConfig,
+    test: &RestoreTestConfig,
+    shutdown: Shutdown,
+) -> anyhow::Result<()> {
+    let dir = test.scratch_dir.join(format!(
+        "pdrun-restore-test-{}-{}",
+        backup.name(),
+        process::id()
+    ));
+
+    logPrint!(
+        "supervisor",
+        "Test restoring latest snapshot of {} into {}",
+        backup.name(),
+        dir.display()
+    );
+
+    let result = restore_and_verify(backup, test, &dir, shutdown).await;
+
+    // Whatever happened, don't leave a copy of the data lying around
+    let cleanup = remove_path(&dir).context("Removing restore test directory");
+    result.and(cleanup)
+}
+
+async fn restore_and_verify(
+    backup: &BackupConfig,
+    test: &RestoreTestConfig,
+    dir: &Path,
+    shutdown: Shutdown,
+) -> anyhow::Result<()> {
+    run_to_end(
+        &format!("{}:restore_test", backup.name()),
+        restore_latest(backup, dir),
+        shutdown.clone(),
+    )
+    .await
+    .context("Restoring latest snapshot")?;
+
+    if let Some(command) = &test.verify_command {
+        let mut cmd = Command::new(&command[0]);
+        cmd.args(&command[1..])
+            .current_dir(dir)
+            .env("PDRUN_RESTORE_DIR", dir);
+
+        return run_to_end(&format!("{}:verify", backup.name()), cmd, shutdown)
+            .await
+            .context("Verifying restored data");
+    }
+
+    let src = backup.src.clone();
+    let dir = dir.to_path_buf();
+    let max_difference = test.max_difference.unwrap_or(DEFAULT_MAX_DIFFERENCE);
+    spawn_blocking(move || compare(&src, &dir, max_difference))
+        .await
+        .context("Comparing restored data")?
+}
+
+fn restore_latest(backup: &BackupConfig, target: &Path) -> Command {
+    let mut cmd = build_restic_command(backup);
+    cmd.args(["restore", "latest", "--target"]).arg(target);
+    add_snapshot_filter(&mut cmd, backup);
+    cmd
+}
+
+/// Restic restores absolute source paths below the target, so `/data` ends up in `target/data`.
+fn restored_path(target: &Path, src: &Path) -> PathBuf {
+    src.components()
+        .filter(|c| matches!(c, Component::Normal(_)))
+        .fold(target.to_path_buf(), |path, c| path.join(c))
+}
+
+fn compare(src: &[PathBuf], target: &Path, max_difference: f64) -> anyhow::Result<()> {
+    for src in src {
+        let restored = restored_path(target, src);
+        if !restored.exists() {
+            bail!("{} is missing from the restored snapshot", src.display());
+        }
+
+        let live = Usage::of(src).with_context(|| format!("Measuring {}", src.display()))?;
+        let test = Usage::of(&restored)
+            .with_context(|| format!("Measuring restored {}", src.display()))?;
+
+        logPrint!(
+            "supervisor",
+            "{} has {} files / {} bytes, restored copy has {} files / {} bytes",
+            src.display(),
+            live.files,
+            live.bytes,
+            test.files,
+            test.bytes
+        );
+
+        for (what, live, test) in [
+            ("file count", live.files, test.files),
+            ("total size", live.bytes, test.bytes),
+        ] {
+            let difference = percent_difference(live, test);
+            if difference > max_difference {
+                bail!(
+                    "Restored {} of {} differs by {difference:.1}% from the live data",
+                    what,
+                    src.display()
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn percent_difference(live: u64, test: u64) -> f64 {
+    if live == 0 {
+        return if test == 0 { 0.0 } else { 100.0 };
+    }
+
+    live.abs_diff(test) as f64 * 100.0 / live as f64
+}
+
+#[derive(Default)]
+struct Usage {
+    files: u64,
+    bytes: u64,
+}
+
+impl Usage {
+    fn of(path: &Path) -> anyhow::Result<Self> {
+        let mut usage = Self::default();
+        usage.add(path)?;
+        Ok(usage)
+    }
+
+    fn add(&mut self, path: &Path) -> anyhow::Result<()> {
+        let metadata = fs::symlink_metadata(path)
+            .with_context(|| format!("Reading metadata of {}", path.display()))?;
+
+        if metadata.is_dir() {
+            let entries =
+                fs::read_dir(path).with_context(|| format!("Listing {}", path.display()))?;
+            for entry in entries {
+                let entry = entry.with_context(|| format!("Listing {}", path.display()))?;
+                self.add(&entry.path())?;
+            }
+        } else {
+            self.files += 1;
+            if metadata.is_file() {
+                self.bytes += metadata.len();
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -1,12 +1,212 @@
+use std::{
+    fs,
+    io::ErrorKind,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    process,
+};
+
+use anyhow::{bail, Context};
+use chrono::Utc;
+use chrono_tz::Tz;
 use tokio::process::Command;
 
-use crate::{config::RestoreConfig, restic::build_restic_command};
+use crate::{
+    config::{RestoreConfig, RestoreStrategy},
+    restic::{build_restic_command, list_snapshots, Snapshot},
+};
+
+/// Written into dst after a successful restore, for the `missing_marker` strategy
+pub const RESTORED_MARKER: &str = ".pdrun-restored";
+
+const DEFAULT_IGNORED_ENTRIES: &[&str] = &["lost+found"];
+
+pub fn needs_restore(r: &RestoreConfig) -> anyhow::Result<bool> {
+    match r.strategy.unwrap_or_default() {
+        RestoreStrategy::Always => Ok(true),
+        RestoreStrategy::MissingMarker => Ok(!r.dst().join(RESTORED_MARKER).exists()),
+        RestoreStrategy::EmptyDstOnly => {
+            let entries = match fs::read_dir(r.dst()) {
+                Ok(entries) => entries,
+                Err(err) if err.kind() == ErrorKind::NotFound => return Ok(true),
+                Err(err) => return Err(err).context("Listing restore destination"),
+            };
+
+            for entry in entries {
+                let name = entry.context("Listing restore destination")?.file_name();
+                let ignored = match &r.ignored_entries {
+                    Some(ignored) => ignored.iter().any(|i| name == i.as_str()),
+                    None => DEFAULT_IGNORED_ENTRIES.iter().any(|i| name == *i),
+                };
+
+                if !ignored {
+                    return Ok(false);
+                }
+            }
 
-pub fn restore(r: &RestoreConfig) -> Command {
+            Ok(true)
+        }
+    }
+}
+
+pub fn write_marker(r: &RestoreConfig) -> anyhow::Result<()> {
+    fs::write(r.dst().join(RESTORED_MARKER), b"").context("Writing restored marker")
+}
+
+/// Find the snapshot to restore: the configured one, or the latest matching the filters.
+pub async fn find_snapshot(r: &RestoreConfig, tz: &Tz) -> anyhow::Result<Snapshot> {
     let mut cmd = build_restic_command(r);
-    cmd.args(["--verbose", "restore", "latest"])
-        .arg("--target")
-        .arg(&r.dst);
+    cmd.args(["snapshots", "--json"]);
+
+    if let Some(host) = &r.host {
+        cmd.arg("--host").arg(host);
+    }
+
+    // An empty `--tag` would only match untagged snapshots
+    if let Some(tags) = &r.tags {
+        if !tags.is_empty() {
+            cmd.arg("--tag").arg(tags.join(","));
+        }
+    }
+
+    for path in r.paths.iter().flatten() {
+        cmd.arg("--path").arg(path);
+    }
+
+    if let Some(id) = &r.snapshot {
+        cmd.arg(id);
+    }
+
+    let before = match &r.before {
+        Some(before) => Some(
+            before
+                .in_timezone(tz)
+                .with_context(|| format!("{before} doesn't exist in {tz}"))?
+                .with_timezone(&Utc),
+        ),
+        None => None,
+    };
+
+    list_snapshots(cmd)
+        .await
+        .context("Listing snapshots")?
+        .into_iter()
+        .filter(|s| before.is_none_or(|before| s.time < before))
+        .max_by_key(|s| s.time)
+        .context("No matching snapshot to restore")
+}
+
+/// Where a restore is written to before being moved into dst: a hidden sibling of dst, or a
+/// hidden directory inside it when dst is a mount point and can't be replaced.
+pub fn staging_dir(r: &RestoreConfig) -> anyhow::Result<PathBuf> {
+    let dst = r.dst();
+    if is_mount_point(dst)? {
+        return Ok(dst.join(format!(".pdrun-restore-{}", process::id())));
+    }
+
+    sibling(dst, "restore")
+}
+
+/// Replace dst with a successfully restored staging directory.
+pub fn move_into_place(r: &RestoreConfig, staging: &Path) -> anyhow::Result<()> {
+    let dst = r.dst();
+
+    if staging.parent() == Some(dst) {
+        for entry in fs::read_dir(staging).context("Listing restored files")? {
+            let entry = entry.context("Listing restored files")?;
+            let target = dst.join(entry.file_name());
+            remove_path(&target)?;
+            fs::rename(entry.path(), &target)
+                .with_context(|| format!("Moving restored file to {}", target.display()))?;
+        }
+
+        return fs::remove_dir(staging).context("Removing staging directory");
+    }
+
+    if fs::symlink_metadata(dst).is_err() {
+        return fs::rename(staging, dst)
+            .with_context(|| format!("Moving restored directory to {}", dst.display()));
+    }
+
+    let old = sibling(dst, "old")?;
+    fs::rename(dst, &old).with_context(|| format!("Moving {} aside", dst.display()))?;
+
+    if let Err(err) = fs::rename(staging, dst) {
+        let _ = fs::rename(&old, dst);
+        return Err(err).with_context(|| format!("Moving restored directory to {}", dst.display()));
+    }
+
+    remove_path(&old)
+}
+
+pub fn remove_path(path: &Path) -> anyhow::Result<()> {
+    let result = match fs::symlink_metadata(path) {
+        Ok(meta) if meta.is_dir() => fs::remove_dir_all(path),
+        Ok(_) => fs::remove_file(path),
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(err) => Err(err),
+    };
+
+    result.with_context(|| format!("Removing {}", path.display()))
+}
+
+fn sibling(dst: &Path, purpose: &str) -> anyhow::Result<PathBuf> {
+    let Some(name) = dst.file_name() else {
+        bail!(
+            "Restore destination {} has no directory name",
+            dst.display()
+        );
+    };
+
+    let parent = dst.parent().unwrap_or(Path::new("."));
+    fs::create_dir_all(parent).with_context(|| format!("Creating {}", parent.display()))?;
+
+    Ok(parent.join(format!(
+        ".{}.pdrun-{purpose}-{}",
+        name.to_string_lossy(),
+        process::id()
+    )))
+}
+
+fn is_mount_point(path: &Path) -> anyhow::Result<bool> {
+    let meta = match fs::metadata(path) {
+        Ok(meta) => meta,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(false),
+        Err(err) => return Err(err).with_context(|| format!("Inspecting {}", path.display())),
+    };
+
+    let Some(parent) = path.parent() else {
+        return Ok(true);
+    };
+
+    let parent_meta =
+        fs::metadata(parent).with_context(|| format!("Inspecting {}", parent.display()))?;
+
+    Ok(meta.dev() != parent_meta.dev())
+}
+
+pub fn restore(r: &RestoreConfig, snapshot: &Snapshot, target: &Path) -> Command {
+    let mut cmd = build_restic_command(r);
+    cmd.args(["--verbose", "restore"]);
+
+    match &r.subfolder {
+        Some(subfolder) => cmd.arg(format!("{}:{}", snapshot.id, subfolder.display())),
+        None => cmd.arg(&snapshot.id),
+    };
+
+    cmd.arg("--target").arg(target);
+
+    if r.verify == Some(true) {
+        cmd.arg("--verify");
+    }
+
+    for pattern in r.include.iter().flatten() {
+        cmd.arg("--include").arg(pattern);
+    }
+
+    for pattern in r.exclude.iter().flatten() {
+        cmd.arg("--exclude").arg(pattern);
+    }
 
     cmd
 }
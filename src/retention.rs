@@ -0,0 +1,43 @@
+use tokio::process::Command;
+
+use crate::{
+    config::{BackupConfig, RetentionConfig},
+    restic::{add_snapshot_filter, build_restic_command},
+};
+
+pub fn forget(backup: &BackupConfig, retention: &RetentionConfig, prune: bool) -> Command {
+    let mut cmd = build_restic_command(backup);
+    cmd.args(["--verbose", "forget"]);
+    add_snapshot_filter(&mut cmd, backup);
+
+    let policies = [
+        ("--keep-last", retention.keep_last),
+        ("--keep-hourly", retention.keep_hourly),
+        ("--keep-daily", retention.keep_daily),
+        ("--keep-weekly", retention.keep_weekly),
+        ("--keep-monthly", retention.keep_monthly),
+        ("--keep-yearly", retention.keep_yearly),
+    ];
+
+    for (flag, value) in policies {
+        if let Some(value) = value {
+            cmd.arg(flag).arg(value.to_string());
+        }
+    }
+
+    if let Some(within) = &retention.keep_within {
+        cmd.arg("--keep-within").arg(within);
+    }
+
+    if prune {
+        cmd.arg("--prune");
+    }
+
+    cmd
+}
+
+pub fn prune(backup: &BackupConfig) -> Command {
+    let mut cmd = build_restic_command(backup);
+    cmd.args(["--verbose", "prune"]);
+    cmd
+}
@@ -1,3 +1,5 @@
+use std::path::Path;
+
 use tokio::process::Command;
 
 use super::config::AppConfig;
@@ -8,7 +10,38 @@ pub fn pull_image(config: &AppConfig) -> Command {
     cmd
 }
 
-pub fn run_app(config: &AppConfig) -> Command {
+/// Point `image` back at an image that's still stored locally, e.g. the one in use before a pull
+pub fn tag_image(image_id: &str, image: &str) -> Command {
+    let mut cmd = Command::new("podman");
+    cmd.arg("tag").arg(image_id).arg(image);
+    cmd
+}
+
+pub fn create_pod<'a>(name: &str, ports: impl IntoIterator<Item = &'a String>) -> Command {
+    let mut cmd = Command::new("podman");
+    cmd.args(["pod", "create", "--replace", "--name"]).arg(name);
+
+    for port in ports {
+        cmd.arg("-p").arg(port);
+    }
+
+    cmd
+}
+
+pub fn remove_pod(name: &str) -> Command {
+    let mut cmd = Command::new("podman");
+    cmd.args(["pod", "rm", "--force", "--ignore"]).arg(name);
+    cmd
+}
+
+/// Environments are read from `env_file` rather than passed on the command line, where any
+/// user on the host could see them.
+pub fn run_app(
+    config: &AppConfig,
+    container_name: &str,
+    pod: Option<&str>,
+    env_file: Option<&Path>,
+) -> Command {
     let mut cmd = Command::new("podman");
 
     cmd.arg("run");
@@ -18,15 +51,18 @@ pub fn run_app(config: &AppConfig) -> Command {
         args,
         volumes,
         cap_add,
-        environments,
+        environments: _,
+        env_file: _,
         ports,
         network_mode,
+        depends_on: _,
+        restart: _,
+        healthcheck: _,
+        container_name: _,
     } = config;
 
-    if let Some(envs) = &environments {
-        for (key, value) in envs {
-            cmd.arg("-e").arg(format!("{key}={value}"));
-        }
+    if let Some(env_file) = env_file {
+        cmd.arg("--env-file").arg(env_file);
     }
 
     if let Some(volumes) = volumes {
@@ -41,16 +77,26 @@ pub fn run_app(config: &AppConfig) -> Command {
         }
     }
 
-    if let Some(ports) = ports {
-        for port in ports {
-            cmd.arg("-p").arg(port);
+    // Ports of containers in a pod are published by the pod itself
+    match (pod, ports) {
+        (Some(pod), _) => {
+            cmd.arg("--pod").arg(pod);
         }
+
+        (None, Some(ports)) => {
+            for port in ports {
+                cmd.arg("-p").arg(port);
+            }
+        }
+
+        (None, None) => {}
     }
 
     if let Some(network_mode) = network_mode {
         cmd.arg("--network").arg(network_mode.to_string());
     }
 
+    cmd.arg("--name").arg(container_name);
     cmd.args(["--rm", "--init"]);
     cmd.arg(image);
 
@@ -60,3 +106,17 @@ pub fn run_app(config: &AppConfig) -> Command {
 
     cmd
 }
+
+/// Prints `true` once the container is running
+pub fn container_running(container_name: &str) -> Command {
+    let mut cmd = Command::new("podman");
+    cmd.args(["container", "inspect", "--format", "{{.State.Running}}"])
+        .arg(container_name);
+    cmd
+}
+
+pub fn exec<'a>(container_name: &str, command: impl IntoIterator<Item = &'a String>) -> Command {
+    let mut cmd = Command::new("podman");
+    cmd.arg("exec").arg(container_name).args(command);
+    cmd
+}
@@ -0,0 +1,222 @@
+use std::{
+    collections::HashMap,
+    env, fs,
+    io::{ErrorKind, Write},
+    os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context};
+use nix::unistd::getuid;
+
+use crate::log::add_secret;
+
+const FILE_PREFIX: &str = "file:";
+const SECRET_PREFIX: &str = "secret:";
+
+/// Resolve the environment of a config section in place: entries from its env files come
+/// first, then its own entries with `${VAR}` and `file:` sources expanded. Values that came
+/// from outside the config file or are marked with `secret:` are registered as secrets, so
+/// they're redacted from the log.
+pub fn resolve_environments(
+    environments: &mut Option<HashMap<String, String>>,
+    env_files: Option<&Vec<PathBuf>>,
+) -> anyhow::Result<()> {
+    let mut resolved = HashMap::new();
+
+    for path in env_files.into_iter().flatten() {
+        let entries = dotenvy::from_path_iter(path)
+            .with_context(|| format!("Opening env file {}", path.display()))?;
+
+        for entry in entries {
+            let (key, value) =
+                entry.with_context(|| format!("Reading env file {}", path.display()))?;
+            add_secret(&value);
+            resolved.insert(key, value);
+        }
+    }
+
+    for (key, value) in environments.iter().flatten() {
+        let value = resolve_value(value).with_context(|| format!("Resolving {key}"))?;
+        resolved.insert(key.clone(), value);
+    }
+
+    if !resolved.is_empty() {
+        *environments = Some(resolved);
+    }
+
+    Ok(())
+}
+
+/// `file:<path>` reads the value from a file, like the ones podman, systemd credentials or
+/// Docker secrets provide, and `secret:<value>` is taken as is. Otherwise `${VAR}` is replaced
+/// with the supervisor's environment variable and `$$` with a literal `$`.
+fn resolve_value(value: &str) -> anyhow::Result<String> {
+    if let Some(secret) = value.strip_prefix(SECRET_PREFIX) {
+        add_secret(secret);
+        return Ok(secret.to_string());
+    }
+
+    if let Some(path) = value.strip_prefix(FILE_PREFIX) {
+        let content = fs::read_to_string(path).with_context(|| format!("Reading {path}"))?;
+        let content = content
+            .strip_suffix('\n')
+            .map(|c| c.strip_suffix('\r').unwrap_or(c))
+            .unwrap_or(&content)
+            .to_string();
+
+        add_secret(&content);
+        return Ok(content);
+    }
+
+    let mut resolved = String::with_capacity(value.len());
+    let mut rest = value;
+
+    while let Some(index) = rest.find('$') {
+        resolved.push_str(&rest[..index]);
+        rest = &rest[index..];
+
+        if let Some(after) = rest.strip_prefix("$$") {
+            resolved.push('$');
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("${") {
+            let Some(end) = after.find('}') else {
+                bail!("Unterminated ${{ in environment value");
+            };
+
+            let name = &after[..end];
+            let var = env::var(name)
+                .with_context(|| format!("Environment variable {name} is not set"))?;
+
+            add_secret(&var);
+            resolved.push_str(&var);
+            rest = &after[end + 1..];
+        } else {
+            resolved.push('$');
+            rest = &rest[1..];
+        }
+    }
+
+    resolved.push_str(rest);
+    Ok(resolved)
+}
+
+/// Where env files for `podman run` go: a directory only the current user can access, in the
+/// user's runtime dir when there is one since that isn't persisted across reboots.
+pub fn env_file_dir() -> anyhow::Result<PathBuf> {
+    let uid = getuid();
+    let dir = match env::var_os("XDG_RUNTIME_DIR") {
+        Some(runtime_dir) => PathBuf::from(runtime_dir).join("pdrun"),
+        None => env::temp_dir().join(format!("pdrun-{uid}")),
+    };
+
+    match fs::DirBuilder::new().mode(0o700).create(&dir) {
+        Err(err) if err.kind() != ErrorKind::AlreadyExists => {
+            return Err(err).with_context(|| format!("Creating {}", dir.display()))
+        }
+        _ => {}
+    }
+
+    // In a shared temp dir, someone else could have created it first
+    let metadata =
+        fs::symlink_metadata(&dir).with_context(|| format!("Inspecting {}", dir.display()))?;
+    if !metadata.is_dir() || metadata.uid() != uid.as_raw() || metadata.mode() & 0o077 != 0 {
+        bail!(
+            "{} has to be a directory only the current user can access",
+            dir.display()
+        );
+    }
+
+    Ok(dir)
+}
+
+/// Write environments in the `KEY=VALUE` format of `podman run --env-file`, readable only by
+/// the current user.
+pub fn write_env_file(path: &Path, environments: &HashMap<String, String>) -> anyhow::Result<()> {
+    let mut content = String::new();
+    for (key, value) in environments {
+        if key.contains(['=', '\n']) || value.contains('\n') {
+            bail!("Environment {key} can't be passed in an env file as it contains a newline or =");
+        }
+        content.push_str(&format!("{key}={value}\n"));
+    }
+
+    // Don't let a leftover file from an earlier run keep its permissions
+    remove_env_file(path)?;
+
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+        .and_then(|mut file| file.write_all(content.as_bytes()))
+        .with_context(|| format!("Writing env file {}", path.display()))
+}
+
+pub fn remove_env_file(path: &Path) -> anyhow::Result<()> {
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != ErrorKind::NotFound => {
+            Err(err).with_context(|| format!("Removing env file {}", path.display()))
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process;
+
+    use super::*;
+
+    #[test]
+    fn plain_values_are_kept() {
+        assert_eq!(resolve_value("8080").unwrap(), "8080");
+        assert_eq!(resolve_value("costs $5").unwrap(), "costs $5");
+        assert_eq!(resolve_value("trailing $").unwrap(), "trailing $");
+    }
+
+    #[test]
+    fn double_dollar_is_a_literal_dollar() {
+        assert_eq!(resolve_value("$${HOME}").unwrap(), "${HOME}");
+        assert_eq!(resolve_value("a$$$$b").unwrap(), "a$$b");
+    }
+
+    #[test]
+    fn variables_are_expanded() {
+        env::set_var("PDRUN_TEST_SECRETS_USER", "alice");
+        assert_eq!(
+            resolve_value("${PDRUN_TEST_SECRETS_USER}@db:$${PORT}").unwrap(),
+            "alice@db:${PORT}"
+        );
+    }
+
+    #[test]
+    fn unterminated_variable_fails() {
+        let err = resolve_value("pre${HOME").unwrap_err();
+        assert_eq!(err.to_string(), "Unterminated ${ in environment value");
+    }
+
+    #[test]
+    fn unset_variable_fails() {
+        let err = resolve_value("${PDRUN_TEST_SECRETS_UNSET}").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Environment variable PDRUN_TEST_SECRETS_UNSET is not set"
+        );
+    }
+
+    #[test]
+    fn file_values_lose_one_trailing_line_break() {
+        let path = env::temp_dir().join(format!("pdrun-test-secret-{}", process::id()));
+        fs::write(&path, "hunter2\r\n").unwrap();
+        let value = resolve_value(&format!("file:{}", path.display()));
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(value.unwrap(), "hunter2");
+    }
+
+    #[test]
+    fn secret_values_are_taken_as_is() {
+        assert_eq!(resolve_value("secret:pa$$w0rd").unwrap(), "pa$$w0rd");
+    }
+}
@@ -0,0 +1,827 @@
+use std::{
+    cell::Cell,
+    collections::VecDeque,
+    future::{pending, Future},
+    path::PathBuf,
+    pin::Pin,
+    process::{self, ExitStatus, Stdio},
+    rc::Rc,
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Context};
+use async_shutdown::Shutdown;
+use futures::future::select_all;
+use serde::Serialize;
+use tokio::{
+    select,
+    task::{spawn_local, JoinHandle},
+    time::{sleep, sleep_until, Instant},
+};
+
+use crate::{
+    config::{AppConfig, Config, RestartConfig, RestartPolicy},
+    health,
+    log::{elogPrint, logPrint},
+    notify::{Notification, Notifier},
+    process::Process,
+    runner, secrets, sleep_until_or_forever,
+};
+
+const DEFAULT_RESTART_WINDOW: Duration = Duration::from_secs(600);
+const DEFAULT_RESTART_BACKOFF: Duration = Duration::from_secs(1);
+const DEFAULT_RESTART_MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// How apps without a restart policy are restarted when they become unhealthy
+const UNHEALTHY_RESTART: RestartConfig = RestartConfig {
+    policy: RestartPolicy::OnFailure,
+    max_retries: None,
+    window: None,
+    backoff: None,
+    max_backoff: None,
+};
+
+/// How often to look whether a dependency's container is running yet
+const READY_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+struct App {
+    name: String,
+    container_name: String,
+    config: AppConfig,
+    process: Option<Process>,
+    restart_at: Option<Instant>,
+    restarts: VecDeque<Instant>,
+    /// Restarts after crashing or becoming unhealthy, over the lifetime of the supervisor.
+    /// Shared with `StatusView`, like `running_since`.
+    restarts_total: Rc<Cell<u64>>,
+    running_since: Rc<Cell<Option<Instant>>>,
+    health: Option<Health>,
+    /// Holds the environments until `podman run` has read them, see `runner::run_app`
+    env_file: Option<PathBuf>,
+    /// Removes `env_file` as soon as the container is running
+    env_file_cleanup: Option<JoinHandle<()>>,
+}
+
+struct Health {
+    started_at: Instant,
+    next_check: Instant,
+    failures: u32,
+    /// Whether the last check passed
+    healthy: bool,
+    /// A probe running in the background, so that `Stack::wait` being cancelled doesn't lose it
+    probe: Option<JoinHandle<anyhow::Result<()>>>,
+}
+
+impl App {
+    fn start(&mut self, pod: Option<&str>, shutdown: &Shutdown) -> anyhow::Result<()> {
+        if let Some(environments) = &self.config.environments {
+            let path = secrets::env_file_dir()?.join(format!("{}.env", self.container_name));
+            secrets::write_env_file(&path, environments)?;
+            self.env_file = Some(path);
+        }
+
+        let process = Process::new(
+            &self.name,
+            runner::run_app(
+                &self.config,
+                &self.container_name,
+                pod,
+                self.env_file.as_deref(),
+            ),
+            shutdown.clone(),
+        )
+        .with_context(|| format!("Starting {} process", self.name))?;
+
+        if let Some(path) = self.env_file.clone() {
+            let container_name = self.container_name.clone();
+            self.env_file_cleanup = Some(spawn_local(async move {
+                while !container_running(&container_name).await {
+                    sleep(READY_POLL_INTERVAL).await;
+                }
+
+                if let Err(err) = secrets::remove_env_file(&path) {
+                    elogPrint!("supervisor", "{err:?}");
+                }
+            }));
+        }
+
+        let now = Instant::now();
+        self.process = Some(process);
+        self.running_since.set(Some(now));
+        self.restart_at = None;
+        self.health = self.config.healthcheck.as_ref().map(|h| Health {
+            started_at: now,
+            next_check: now + h.interval(),
+            failures: 0,
+            healthy: false,
+            probe: None,
+        });
+
+        Ok(())
+    }
+
+    async fn stop(&mut self) -> anyhow::Result<()> {
+        self.restart_at = None;
+        self.clear_health();
+
+        // Only forget the process once it's gone, so that being cancelled halfway leaves an
+        // exited process behind for `Stack::wait` to pick up
+        if let Some(process) = &mut self.process {
+            process
+                .terminate_and_wait()
+                .await
+                .with_context(|| format!("Terminating {}", self.name))?;
+        }
+
+        self.process = None;
+        self.running_since.set(None);
+        self.remove_env_file();
+        Ok(())
+    }
+
+    fn clear_health(&mut self) {
+        if let Some(probe) = self.health.take().and_then(|h| h.probe) {
+            probe.abort();
+        }
+    }
+
+    fn remove_env_file(&mut self) {
+        if let Some(cleanup) = self.env_file_cleanup.take() {
+            cleanup.abort();
+        }
+
+        if let Some(path) = self.env_file.take() {
+            if let Err(err) = secrets::remove_env_file(&path) {
+                elogPrint!("supervisor", "{err:?}");
+            }
+        }
+    }
+
+    /// Run the healthcheck if the app has one. Returns an error once the app has failed
+    /// enough consecutive checks to be considered unhealthy, or `Ok(true)` if it passed.
+    async fn check_health(&mut self, client: &reqwest::Client) -> anyhow::Result<bool> {
+        let Some(healthcheck) = &self.config.healthcheck else {
+            return Ok(true);
+        };
+
+        let result = health::check(client, healthcheck, &self.container_name).await;
+        self.record_health(result)
+    }
+
+    /// Start the healthcheck in the background, see `Stack::wait`.
+    fn start_probe(&mut self, client: &reqwest::Client) {
+        let (Some(healthcheck), Some(health)) = (&self.config.healthcheck, &mut self.health) else {
+            return;
+        };
+
+        let client = client.clone();
+        let healthcheck = healthcheck.clone();
+        let container_name = self.container_name.clone();
+
+        health.probe = Some(spawn_local(async move {
+            health::check(&client, &healthcheck, &container_name).await
+        }));
+    }
+
+    fn record_health(&mut self, result: anyhow::Result<()>) -> anyhow::Result<bool> {
+        let (Some(healthcheck), Some(health)) = (&self.config.healthcheck, &mut self.health) else {
+            return Ok(true);
+        };
+
+        health.probe = None;
+        health.next_check = Instant::now() + healthcheck.interval();
+
+        let err = match result {
+            Ok(()) => {
+                health.failures = 0;
+                health.healthy = true;
+                return Ok(true);
+            }
+            Err(err) => err,
+        };
+
+        health.healthy = false;
+
+        if health.started_at.elapsed() < healthcheck.start_period() {
+            return Ok(false);
+        }
+
+        health.failures += 1;
+        logPrint!(
+            "supervisor",
+            "Healthcheck of {} failed ({}/{}): {err:?}",
+            self.name,
+            health.failures,
+            healthcheck.retries()
+        );
+
+        if health.failures >= healthcheck.retries() {
+            return Err(err.context(format!("{} is unhealthy", self.name)));
+        }
+
+        Ok(false)
+    }
+
+    /// Decide whether the app should be restarted after exiting or becoming unhealthy, and
+    /// after how long. Unhealthy apps without a restart policy are restarted with the defaults.
+    fn restart_delay(
+        &mut self,
+        status: &anyhow::Result<ExitStatus>,
+        unhealthy: bool,
+    ) -> Option<Duration> {
+        let restart = match (&self.config.restart, unhealthy) {
+            (Some(restart), _) => restart,
+            (None, true) => &UNHEALTHY_RESTART,
+            (None, false) => return None,
+        };
+
+        let failed = !matches!(status, Ok(status) if status.success());
+        match restart.policy {
+            RestartPolicy::Never => return None,
+            RestartPolicy::OnFailure if !failed => return None,
+            RestartPolicy::OnFailure | RestartPolicy::Always => {}
+        }
+
+        let now = Instant::now();
+        let window = restart.window.unwrap_or(DEFAULT_RESTART_WINDOW);
+        while matches!(self.restarts.front(), Some(t) if now.duration_since(*t) > window) {
+            self.restarts.pop_front();
+        }
+
+        if let Some(max_retries) = restart.max_retries {
+            if self.restarts.len() >= max_retries as usize {
+                logPrint!(
+                    "supervisor",
+                    "{} restarted {} times within {window:?}, giving up",
+                    self.name,
+                    self.restarts.len()
+                );
+                return None;
+            }
+        }
+
+        let backoff = restart.backoff.unwrap_or(DEFAULT_RESTART_BACKOFF);
+        let max_backoff = restart.max_backoff.unwrap_or(DEFAULT_RESTART_MAX_BACKOFF);
+        let delay = backoff
+            .checked_mul(1 << self.restarts.len().min(16))
+            .unwrap_or(max_backoff)
+            .min(max_backoff);
+
+        self.restarts.push_back(now);
+        self.restarts_total.set(self.restarts_total.get() + 1);
+        Some(delay)
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct AppStatus {
+    pub name: String,
+    pub container_name: String,
+    pub image: String,
+    pub running: bool,
+    /// Seconds since the app was last started
+    pub uptime: Option<f64>,
+    /// Restarts after crashing or becoming unhealthy
+    pub restarts: u64,
+}
+
+/// The apps' statuses, kept current by the stack even while it's busy, e.g. stopped for a
+/// backup.
+#[derive(Clone)]
+pub struct StatusView {
+    apps: Vec<AppStatusView>,
+}
+
+#[derive(Clone)]
+struct AppStatusView {
+    /// The parts that don't change, the rest is filled in from the cells
+    status: AppStatus,
+    running_since: Rc<Cell<Option<Instant>>>,
+    restarts_total: Rc<Cell<u64>>,
+}
+
+impl StatusView {
+    pub fn get(&self) -> Vec<AppStatus> {
+        self.apps
+            .iter()
+            .map(|app| {
+                let running_since = app.running_since.get();
+                AppStatus {
+                    running: running_since.is_some(),
+                    uptime: running_since.map(|since| since.elapsed().as_secs_f64()),
+                    restarts: app.restarts_total.get(),
+                    ..app.status.clone()
+                }
+            })
+            .collect()
+    }
+}
+
+/// The set of apps from one config file, started in dependency order and stopped in reverse.
+pub struct Stack {
+    apps: Vec<App>,
+    pod: Option<String>,
+    notifier: Notifier,
+    /// Shared by the HTTP healthchecks
+    client: reqwest::Client,
+    shutdown: Shutdown,
+}
+
+impl Stack {
+    pub fn new(config: &Config, notifier: Notifier, shutdown: Shutdown) -> anyhow::Result<Self> {
+        let apps = config
+            .apps_in_start_order()?
+            .into_iter()
+            .map(|(name, config)| App {
+                container_name: config
+                    .container_name
+                    .clone()
+                    .unwrap_or_else(|| format!("pdrun-{}-{name}", process::id())),
+                name,
+                config,
+                process: None,
+                restart_at: None,
+                restarts: Default::default(),
+                restarts_total: Rc::default(),
+                running_since: Rc::default(),
+                health: None,
+                env_file: None,
+                env_file_cleanup: None,
+            })
+            .collect();
+
+        let client = reqwest::Client::builder()
+            .build()
+            .context("Building HTTP client")?;
+
+        Ok(Self {
+            apps,
+            pod: config.pod.clone(),
+            notifier,
+            client,
+            shutdown,
+        })
+    }
+
+    pub fn apps(&self) -> impl Iterator<Item = (&str, &AppConfig)> {
+        self.apps.iter().map(|app| (app.name.as_str(), &app.config))
+    }
+
+    pub fn status_view(&self) -> StatusView {
+        let apps = self
+            .apps
+            .iter()
+            .map(|app| AppStatusView {
+                status: AppStatus {
+                    name: app.name.clone(),
+                    container_name: app.container_name.clone(),
+                    image: app.config.image.clone(),
+                    running: false,
+                    uptime: None,
+                    restarts: 0,
+                },
+                running_since: app.running_since.clone(),
+                restarts_total: app.restarts_total.clone(),
+            })
+            .collect();
+
+        StatusView { apps }
+    }
+
+    pub fn container_name(&self, name: &str) -> Option<&str> {
+        self.apps
+            .iter()
+            .find(|app| app.name == name)
+            .map(|app| app.container_name.as_str())
+    }
+
+    pub async fn create_pod(&self) -> anyhow::Result<()> {
+        let Some(pod) = &self.pod else {
+            return Ok(());
+        };
+
+        logPrint!("supervisor", "Creating pod {pod}");
+
+        let ports = self
+            .apps
+            .iter()
+            .flat_map(|app| app.config.ports.iter().flatten());
+
+        let mut process =
+            Process::new("pod", runner::create_pod(pod, ports), self.shutdown.clone())
+                .context("Starting pod creation process")?;
+
+        if !process
+            .wait()
+            .await
+            .context("Waiting for pod creation process")?
+            .success()
+        {
+            bail!("Failed creating pod {pod}");
+        }
+
+        Ok(())
+    }
+
+    pub async fn remove_pod(&self) {
+        let Some(pod) = &self.pod else {
+            return;
+        };
+
+        // The supervisor is shutting down at this point, so don't let its shutdown cancel the removal
+        match Process::new("pod", runner::remove_pod(pod), Shutdown::new()) {
+            Ok(mut process) => {
+                let _ = process.wait().await;
+            }
+            Err(err) => {
+                logPrint!("supervisor", "Unable to remove pod {pod}: {err:?}");
+            }
+        }
+    }
+
+    /// Start the apps that aren't running. Before an app is started, the apps it depends on
+    /// have to be running, and healthy if they have a healthcheck.
+    pub async fn start(&mut self) -> anyhow::Result<()> {
+        for index in 0..self.apps.len() {
+            if self.apps[index].process.is_some() {
+                continue;
+            }
+
+            let name = self.apps[index].name.clone();
+            for dep in self.apps[index]
+                .config
+                .depends_on
+                .clone()
+                .into_iter()
+                .flatten()
+            {
+                self.wait_ready(&dep)
+                    .await
+                    .with_context(|| format!("Waiting for {dep} before starting {name}"))?;
+            }
+
+            self.apps[index].start(self.pod.as_deref(), &self.shutdown)?;
+        }
+
+        Ok(())
+    }
+
+    /// Wait until the app's container is running and it has passed its healthcheck.
+    pub async fn wait_ready(&mut self, name: &str) -> anyhow::Result<()> {
+        let app = find_app(&mut self.apps, name)?;
+
+        for attempt in 0.. {
+            if container_running(&app.container_name).await {
+                break;
+            }
+            if attempt == 0 {
+                logPrint!("supervisor", "Waiting for {name} to be running");
+            }
+
+            let process = app.process.as_mut().context("App is not running")?;
+            select! {
+                status = process.wait() => {
+                    bail!("{name} exited with {:?} before it was running", status?.code())
+                }
+                _ = sleep(READY_POLL_INTERVAL) => {}
+            }
+        }
+
+        self.wait_healthy(name).await
+    }
+
+    pub async fn stop(&mut self) {
+        for app in self.apps.iter_mut().rev() {
+            if app.process.is_some() {
+                logPrint!("supervisor", "Stopping {}", app.name);
+            }
+            let _ = app.stop().await;
+        }
+    }
+
+    pub async fn restart(&mut self, name: &str) -> anyhow::Result<()> {
+        let app = find_app(&mut self.apps, name)?;
+        app.stop().await?;
+        app.start(self.pod.as_deref(), &self.shutdown)
+    }
+
+    /// Wait until the app has passed its healthcheck, failing if it becomes unhealthy or exits first.
+    /// Apps without a healthcheck are considered healthy as soon as they are running.
+    pub async fn wait_healthy(&mut self, name: &str) -> anyhow::Result<()> {
+        let app = find_app(&mut self.apps, name)?;
+
+        loop {
+            let Some(health) = &app.health else {
+                return Ok(());
+            };
+            if health.healthy {
+                return Ok(());
+            }
+
+            let next_check = health.next_check;
+            let process = app.process.as_mut().context("App is not running")?;
+
+            select! {
+                status = process.wait() => {
+                    bail!("{name} exited with {:?} before becoming healthy", status?.code())
+                }
+                _ = sleep_until(next_check) => {}
+            }
+
+            if app.check_health(&self.client).await? {
+                logPrint!("supervisor", "{name} is healthy");
+                return Ok(());
+            }
+        }
+    }
+
+    /// Wait until the app has passed its healthcheck and stayed up for at least `grace_period`
+    /// since it was started.
+    pub async fn wait_stable(&mut self, name: &str, grace_period: Duration) -> anyhow::Result<()> {
+        let deadline = Instant::now() + grace_period;
+        self.wait_healthy(name).await?;
+
+        let app = find_app(&mut self.apps, name)?;
+        let process = app.process.as_mut().context("App is not running")?;
+
+        select! {
+            status = process.wait() => {
+                bail!("{name} exited with {:?} within its grace period", status?.code())
+            }
+            _ = sleep_until(deadline) => Ok(()),
+        }
+    }
+
+    /// Wait for an app to exit or become unhealthy without being restarted according to its
+    /// restart policy, returning its name and exit status. Apps are restarted in the meantime.
+    pub async fn wait(&mut self) -> (String, anyhow::Result<ExitStatus>) {
+        loop {
+            let next_restart = self.apps.iter().filter_map(|app| app.restart_at).min();
+            let next_check = self
+                .apps
+                .iter()
+                .filter_map(|app| app.health.as_ref())
+                .filter(|h| h.probe.is_none())
+                .map(|h| h.next_check)
+                .min();
+
+            let event = select! {
+                event = wait_any(&mut self.apps) => event,
+                _ = sleep_until_or_forever(next_restart) => Event::RestartDue,
+                _ = sleep_until_or_forever(next_check) => Event::HealthcheckDue,
+            };
+
+            let (index, status) = match event {
+                Event::Exited(exited) => exited,
+                Event::Checked((index, result)) => match self.handle_health(index, result).await {
+                    Some(exited) => return exited,
+                    None => continue,
+                },
+                Event::RestartDue => {
+                    if let Err(err) = self.start_due_restarts() {
+                        return ("supervisor".to_string(), Err(err));
+                    }
+                    continue;
+                }
+                Event::HealthcheckDue => {
+                    let now = Instant::now();
+                    for app in &mut self.apps {
+                        if matches!(&app.health, Some(h) if h.probe.is_none() && h.next_check <= now)
+                        {
+                            app.start_probe(&self.client);
+                        }
+                    }
+                    continue;
+                }
+            };
+
+            let app = &mut self.apps[index];
+            app.process = None;
+            app.running_since.set(None);
+            app.clear_health();
+            app.remove_env_file();
+
+            if self.shutdown.shutdown_started() {
+                return (app.name.clone(), status);
+            }
+
+            if !matches!(&status, Ok(status) if status.success()) {
+                self.notifier
+                    .notify(Notification::app_crashed(&app.name, &status));
+            }
+
+            match app.restart_delay(&status, false) {
+                Some(delay) => {
+                    logPrint!(
+                        "supervisor",
+                        "Restarting {} in {delay:?} (restart #{} in window)",
+                        app.name,
+                        app.restarts.len()
+                    );
+                    app.restart_at = Some(Instant::now() + delay);
+                }
+                None => return (app.name.clone(), status),
+            }
+        }
+    }
+
+    fn start_due_restarts(&mut self) -> anyhow::Result<()> {
+        let now = Instant::now();
+        for app in &mut self.apps {
+            if matches!(app.restart_at, Some(at) if at <= now) {
+                app.start(self.pod.as_deref(), &self.shutdown)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Record a finished healthcheck. An unhealthy app is stopped and restarted according to
+    /// its restart policy, or returned like an app that exited if it isn't restarted.
+    async fn handle_health(
+        &mut self,
+        index: usize,
+        result: anyhow::Result<()>,
+    ) -> Option<(String, anyhow::Result<ExitStatus>)> {
+        let app = &mut self.apps[index];
+        let Err(err) = app.record_health(result) else {
+            return None;
+        };
+
+        elogPrint!("supervisor", "{err:?}");
+        let status = Err(err);
+        self.notifier
+            .notify(Notification::app_crashed(&app.name, &status));
+
+        if let Err(err) = app.stop().await {
+            return Some((app.name.clone(), Err(err)));
+        }
+
+        match app.restart_delay(&status, true) {
+            Some(delay) => {
+                logPrint!(
+                    "supervisor",
+                    "Restarting unhealthy {} in {delay:?} (restart #{} in window)",
+                    app.name,
+                    app.restarts.len()
+                );
+                app.restart_at = Some(Instant::now() + delay);
+                None
+            }
+            None => Some((app.name.clone(), status)),
+        }
+    }
+}
+
+enum Event {
+    Exited((usize, anyhow::Result<ExitStatus>)),
+    Checked((usize, anyhow::Result<()>)),
+    RestartDue,
+    HealthcheckDue,
+}
+
+/// Wait for any of the running apps to exit, or any healthcheck running in the background to
+/// finish.
+async fn wait_any(apps: &mut [App]) -> Event {
+    let mut waits: Vec<Pin<Box<dyn Future<Output = Event> + '_>>> = Vec::new();
+
+    for (index, app) in apps.iter_mut().enumerate() {
+        let App {
+            process, health, ..
+        } = app;
+
+        if let Some(process) = process {
+            waits.push(Box::pin(async move {
+                Event::Exited((index, process.wait().await))
+            }));
+        }
+
+        if let Some(probe) = health.as_mut().and_then(|h| h.probe.as_mut()) {
+            waits.push(Box::pin(async move {
+                let result = probe
+                    .await
+                    .unwrap_or_else(|err| Err(anyhow!("Healthcheck panicked: {err}")));
+                Event::Checked((index, result))
+            }));
+        }
+    }
+
+    if waits.is_empty() {
+        return pending().await;
+    }
+
+    select_all(waits).await.0
+}
+
+async fn container_running(container_name: &str) -> bool {
+    let output = runner::container_running(container_name)
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .output()
+        .await;
+
+    matches!(output, Ok(output) if output.status.success() && output.stdout.trim_ascii() == b"true")
+}
+
+fn find_app<'a>(apps: &'a mut [App], name: &str) -> anyhow::Result<&'a mut App> {
+    apps.iter_mut()
+        .find(|app| app.name == name)
+        .with_context(|| format!("Unknown app {name}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::process::ExitStatusExt;
+
+    use super::*;
+
+    fn app(config: &str) -> App {
+        App {
+            name: "web".to_string(),
+            container_name: "pdrun-web".to_string(),
+            config: serde_yaml::from_str(config).unwrap(),
+            process: None,
+            restart_at: None,
+            restarts: VecDeque::new(),
+            restarts_total: Rc::default(),
+            running_since: Rc::default(),
+            health: None,
+            env_file: None,
+            env_file_cleanup: None,
+        }
+    }
+
+    fn exited(code: i32) -> anyhow::Result<ExitStatus> {
+        Ok(ExitStatus::from_raw(code << 8))
+    }
+
+    /// What `Stack::handle_health` passes on
+    fn unhealthy() -> anyhow::Result<ExitStatus> {
+        Err(anyhow!("web failed 3 healthchecks in a row"))
+    }
+
+    const ON_FAILURE: &str = "
+image: nginx
+restart:
+  policy: on-failure
+  max_retries: 5
+  window: 600
+  backoff: 2
+  max_backoff: 10
+";
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let mut app = app(ON_FAILURE);
+
+        let delays: Vec<_> = (0..5)
+            .map(|_| app.restart_delay(&exited(1), false).unwrap().as_secs())
+            .collect();
+
+        assert_eq!(delays, [2, 4, 8, 10, 10]);
+        assert_eq!(app.restarts_total.get(), 5);
+    }
+
+    #[test]
+    fn gives_up_after_max_retries_within_the_window() {
+        let mut app = app(ON_FAILURE);
+
+        for _ in 0..5 {
+            assert!(app.restart_delay(&exited(1), false).is_some());
+        }
+        assert_eq!(app.restart_delay(&exited(1), false), None);
+    }
+
+    #[test]
+    fn restarts_outside_the_window_are_forgotten() {
+        let mut app = app(ON_FAILURE);
+        let long_ago = Instant::now() - Duration::from_secs(601);
+        app.restarts.extend([long_ago; 5]);
+
+        assert_eq!(
+            app.restart_delay(&exited(1), false),
+            Some(Duration::from_secs(2))
+        );
+        assert_eq!(app.restarts.len(), 1);
+    }
+
+    #[test]
+    fn on_failure_ignores_clean_exits() {
+        let mut app = app(ON_FAILURE);
+
+        assert_eq!(app.restart_delay(&exited(0), false), None);
+        assert!(app.restart_delay(&unhealthy(), true).is_some());
+    }
+
+    #[test]
+    fn unhealthy_apps_restart_without_a_policy() {
+        let mut app = app("image: nginx");
+
+        assert_eq!(app.restart_delay(&exited(1), false), None);
+        assert_eq!(
+            app.restart_delay(&unhealthy(), true),
+            Some(DEFAULT_RESTART_BACKOFF)
+        );
+    }
+}
@@ -0,0 +1,151 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    backup::BackupSummary,
+    image_info::ImageInfo,
+    log::{elogPrint, redact},
+    targets::BackupJob,
+};
+
+/// What the supervisor remembers between runs, so that a restart doesn't reset every schedule.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct State {
+    #[serde(default)]
+    pub update: JobState,
+    /// Image each app was last deployed with, by app name
+    #[serde(default)]
+    pub images: BTreeMap<String, ImageState>,
+    /// IDs of images that failed to start after an update, so they aren't deployed again
+    #[serde(default)]
+    pub bad_images: BTreeSet<String>,
+    /// Job states by backup target name
+    #[serde(default)]
+    pub targets: BTreeMap<String, BTreeMap<BackupJob, JobState>>,
+    /// Statistics of the last successful backup, by backup target name
+    #[serde(default)]
+    pub backup_summaries: BTreeMap<String, BackupSummary>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct JobState {
+    /// What the next run is scheduled from: the last success, or the last failure that
+    /// retries gave up on. Not necessarily a completed run, see `last_success` for that.
+    pub scheduled_from: Option<DateTime<Utc>>,
+    pub last_attempt: Option<DateTime<Utc>>,
+    pub last_success: Option<DateTime<Utc>>,
+    /// How long the last attempt took, in seconds
+    pub last_duration: Option<f64>,
+    /// Error of the last attempt, if it failed
+    pub last_error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ImageState {
+    pub id: String,
+    pub digest: String,
+    /// When an update last deployed a new image
+    pub applied_at: Option<DateTime<Utc>>,
+}
+
+impl JobState {
+    pub fn record(&mut self, result: &anyhow::Result<()>, advances_schedule: bool, took: Duration) {
+        let now = Utc::now();
+        self.last_attempt = Some(now);
+        self.last_duration = Some(took.as_secs_f64());
+        self.last_error = result.as_ref().err().map(|err| redact(format!("{err:#}")));
+
+        if result.is_ok() {
+            self.last_success = Some(now);
+        }
+        if advances_schedule {
+            self.scheduled_from = Some(now);
+        }
+    }
+}
+
+impl From<&ImageInfo> for ImageState {
+    fn from(image: &ImageInfo) -> Self {
+        Self {
+            id: image.id.clone(),
+            digest: image.digest.clone(),
+            applied_at: None,
+        }
+    }
+}
+
+impl State {
+    pub fn job(&mut self, target: &str, job: BackupJob) -> &mut JobState {
+        self.targets
+            .entry(target.to_string())
+            .or_default()
+            .entry(job)
+            .or_default()
+    }
+}
+
+/// The state along with where it's persisted. Without a configured state dir nothing is written.
+pub struct StateFile {
+    path: Option<PathBuf>,
+    pub state: State,
+}
+
+impl StateFile {
+    pub fn load(path: Option<PathBuf>) -> anyhow::Result<Self> {
+        let state = match &path {
+            Some(path) => match fs::read(path) {
+                Ok(data) => serde_json::from_slice(&data)
+                    .with_context(|| format!("Parsing state file {}", path.display()))?,
+                Err(err) if err.kind() == ErrorKind::NotFound => State::default(),
+                Err(err) => {
+                    return Err(err)
+                        .with_context(|| format!("Reading state file {}", path.display()))
+                }
+            },
+            None => State::default(),
+        };
+
+        Ok(Self { path, state })
+    }
+
+    /// Write the state out, logging rather than failing since it's only an optimisation.
+    pub fn save(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+
+        if let Err(err) = write_atomically(path, &self.state) {
+            elogPrint!("supervisor", "Unable to save state: {err:?}");
+        }
+    }
+}
+
+/// Write to a temporary file next to the destination and rename it over, so that a crash
+/// halfway through never leaves a truncated state file behind.
+fn write_atomically(path: &Path, state: &State) -> anyhow::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).with_context(|| format!("Creating {}", dir.display()))?;
+    }
+
+    let data = serde_json::to_vec_pretty(state).context("Serializing state")?;
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+
+    // Synced before renaming, otherwise a crash can leave the renamed file empty
+    let mut file = fs::File::create(&tmp).with_context(|| format!("Creating {}", tmp.display()))?;
+    file.write_all(&data)
+        .and_then(|_| file.sync_all())
+        .with_context(|| format!("Writing {}", tmp.display()))?;
+
+    fs::rename(&tmp, path).with_context(|| format!("Renaming {}", tmp.display()))
+}
@@ -0,0 +1,472 @@
+use std::{collections::HashMap, time::Duration};
+
+use anyhow::{bail, Context};
+use async_shutdown::Shutdown;
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use serde_with::{DeserializeFromStr, SerializeDisplay};
+use strum::{Display, EnumString};
+use tokio::time::Instant;
+
+use crate::{
+    backup::{self, BackupSummary},
+    check,
+    config::{self, BackupConfig, CheckConfig, HookConfig, Interval, RetentionConfig},
+    log::{elogPrint, logPrint},
+    notify::{Notification, Notifier},
+    ping::Pinger,
+    process::{run_to_end, run_to_end_with_output, Process},
+    restore_test, retention,
+    stack::Stack,
+    state::{State, StateFile},
+};
+
+const DEFAULT_BACKUP_RETRY_BACKOFF: Duration = Duration::from_secs(60);
+
+#[derive(
+    Display,
+    EnumString,
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    SerializeDisplay,
+    DeserializeFromStr,
+)]
+#[strum(serialize_all = "snake_case")]
+pub enum BackupJob {
+    Backup,
+    Forget,
+    Prune,
+    Check,
+    RestoreTest,
+}
+
+impl BackupJob {
+    pub const ALL: [BackupJob; 5] = [
+        Self::Backup,
+        Self::Forget,
+        Self::Prune,
+        Self::Check,
+        Self::RestoreTest,
+    ];
+}
+
+/// A backup target along with what each of its jobs is scheduled from.
+#[derive(Clone)]
+pub struct BackupTarget {
+    pub config: BackupConfig,
+    scheduled_from: HashMap<BackupJob, DateTime<Tz>>,
+    /// Consecutive failed backups, reset on success or once retries run out
+    failures: u32,
+    retry_at: Option<Instant>,
+}
+
+impl BackupTarget {
+    /// Create the target, picking up its schedules from the persisted state.
+    pub fn new(config: BackupConfig, state: &State, tz: Tz) -> Self {
+        let scheduled_from = state
+            .targets
+            .get(config.name())
+            .into_iter()
+            .flatten()
+            .filter_map(|(job, s)| Some((*job, s.scheduled_from?.with_timezone(&tz))))
+            .collect();
+
+        Self {
+            config,
+            scheduled_from,
+            failures: 0,
+            retry_at: None,
+        }
+    }
+
+    pub fn scheduled_from(&self, job: BackupJob) -> Option<DateTime<Tz>> {
+        self.scheduled_from.get(&job).copied()
+    }
+
+    pub fn name(&self) -> &str {
+        self.config.name()
+    }
+
+    pub fn schedule_from(&mut self, job: BackupJob, at: DateTime<Tz>) {
+        self.scheduled_from.insert(job, at);
+    }
+
+    /// How long until the job is due, or `None` if it's not scheduled at all.
+    pub fn next_run(&self, job: BackupJob, now: DateTime<Tz>) -> Option<Duration> {
+        if let (BackupJob::Backup, Some(retry_at)) = (job, self.retry_at) {
+            return Some(retry_at.saturating_duration_since(Instant::now()));
+        }
+
+        self.interval(job)?
+            .next(self.scheduled_from.get(&job).copied(), now)
+    }
+
+    /// Record the outcome of a backup. A failed one is retried with backoff, and once retries
+    /// run out the target waits for its next scheduled backup, without the failure counting as
+    /// its last backup. Returns whether the next backup is now scheduled from `now`.
+    fn finish_backup(&mut self, result: &anyhow::Result<()>, now: DateTime<Tz>) -> bool {
+        self.retry_at = None;
+
+        if result.is_err() && self.failures < self.config.retries.unwrap_or_default() {
+            let backoff = self
+                .config
+                .retry_backoff
+                .unwrap_or(DEFAULT_BACKUP_RETRY_BACKOFF)
+                .saturating_mul(1 << self.failures.min(16));
+
+            self.failures += 1;
+            self.retry_at = Some(Instant::now() + backoff);
+
+            logPrint!(
+                "supervisor",
+                "Retrying backup of {} in {backoff:?} ({}/{})",
+                self.name(),
+                self.failures,
+                self.config.retries.unwrap_or_default()
+            );
+            return false;
+        }
+
+        if result.is_err() {
+            logPrint!(
+                "supervisor",
+                "Giving up on backup of {}, waiting for the next scheduled one",
+                self.name()
+            );
+        }
+
+        self.failures = 0;
+        self.schedule_from(BackupJob::Backup, now);
+        true
+    }
+
+    pub fn is_scheduled(&self, job: BackupJob) -> bool {
+        self.interval(job).is_some()
+    }
+
+    fn ping_url(&self, job: BackupJob) -> Option<&str> {
+        match job {
+            BackupJob::Backup => self.config.ping_url.as_deref(),
+            BackupJob::Check => self.config.check.as_ref()?.ping_url.as_deref(),
+            BackupJob::RestoreTest => self.config.restore_test.as_ref()?.ping_url.as_deref(),
+            BackupJob::Forget | BackupJob::Prune => None,
+        }
+    }
+
+    fn interval(&self, job: BackupJob) -> Option<&Interval> {
+        match job {
+            BackupJob::Backup => Some(&self.config.interval),
+            BackupJob::Forget => self.config.retention.as_ref()?.interval.as_ref(),
+            BackupJob::Prune => self.config.retention.as_ref()?.prune_interval.as_ref(),
+            BackupJob::Check => self.config.check.as_ref().map(|c| &c.interval),
+            BackupJob::RestoreTest => self.config.restore_test.as_ref().map(|t| &t.interval),
+        }
+    }
+}
+
+/// How one target's backup went.
+struct BackupOutcome {
+    result: anyhow::Result<Option<BackupSummary>>,
+    /// How long restic took to back up the target, zero if it didn't get to run
+    took: Duration,
+}
+
+/// Run the backups that are due together, so that apps only have to be stopped once.
+/// Returns the outcome of each backup.
+async fn start_backups(
+    backups: &[&BackupConfig],
+    stack: &mut Stack,
+    notifier: &Notifier,
+    shutdown: Shutdown,
+) -> anyhow::Result<Vec<BackupOutcome>> {
+    let mut outcomes = Vec::with_capacity(backups.len());
+    for backup in backups {
+        let pre = backup.hooks.as_ref().and_then(|h| h.pre.as_ref());
+        outcomes.push(BackupOutcome {
+            result: run_hooks(pre, stack, &shutdown)
+                .await
+                .context("Running pre-backup hooks")
+                .map(|_| None),
+            took: Duration::ZERO,
+        });
+    }
+
+    let stopping_app = backups.iter().zip(&outcomes).any(|(backup, outcome)| {
+        outcome.result.is_ok()
+            && backup.strategy.unwrap_or_default() == config::BackupStrategy::StopApp
+    });
+
+    if stopping_app {
+        logPrint!("supervisor", "Stopping apps before starting backup");
+        stack.stop().await;
+    }
+
+    // Timed one by one, since the targets are backed up one after another
+    for (backup, outcome) in backups.iter().zip(&mut outcomes) {
+        if outcome.result.is_ok() {
+            let started = Instant::now();
+            outcome.result =
+                run_to_end_with_output(backup.name(), backup::backup(backup), shutdown.clone())
+                    .await
+                    .context("Failed backing up app")
+                    .map(|output| backup::parse_summary(&output));
+            outcome.took = started.elapsed();
+        }
+    }
+
+    // Post hooks run even if the apps can't be started again, that failure is returned after
+    let restarted = if stopping_app {
+        logPrint!("supervisor", "Starting apps after backup");
+        stack.start().await
+    } else {
+        Ok(())
+    };
+
+    for (backup, outcome) in backups.iter().zip(&mut outcomes) {
+        if let (Ok(_), Some(retention)) = (&outcome.result, &backup.retention) {
+            if retention.interval.is_none() {
+                let started = Instant::now();
+                let result = start_forget(backup, retention, shutdown.clone()).await;
+                report(
+                    notifier,
+                    backup.name(),
+                    BackupJob::Forget,
+                    started.elapsed(),
+                    &result,
+                );
+            }
+        }
+
+        let post = backup.hooks.as_ref().and_then(|h| h.post.as_ref());
+        let post_result = run_hooks(post, stack, &shutdown)
+            .await
+            .context("Running post-backup hooks");
+
+        if let (Ok(_), Err(err)) = (&outcome.result, post_result) {
+            outcome.result = Err(err);
+        }
+    }
+
+    restarted?;
+    Ok(outcomes)
+}
+
+/// Where the outcomes of jobs are reported to.
+#[derive(Clone, Copy)]
+pub struct Reporting<'a> {
+    pub notifier: &'a Notifier,
+    pub pinger: &'a Pinger,
+}
+
+/// Run the jobs that are due, reporting and recording the outcome of each. Failed jobs don't
+/// fail the supervisor, only being unable to start the apps again after a backup does.
+pub async fn run_backup_jobs(
+    targets: &mut [BackupTarget],
+    due: &[(usize, BackupJob)],
+    stack: &mut Stack,
+    tz: Tz,
+    state: &mut StateFile,
+    reporting: Reporting<'_>,
+    shutdown: Shutdown,
+) -> anyhow::Result<()> {
+    let Reporting { notifier, pinger } = reporting;
+
+    let backup_indices: Vec<usize> = due
+        .iter()
+        .filter(|(_, job)| *job == BackupJob::Backup)
+        .map(|(index, _)| *index)
+        .collect();
+
+    if !backup_indices.is_empty() {
+        let backups: Vec<&BackupConfig> = backup_indices
+            .iter()
+            .map(|index| &targets[*index].config)
+            .collect();
+
+        let pings: Vec<_> = backup_indices
+            .iter()
+            .map(|index| pinger.start(targets[*index].ping_url(BackupJob::Backup)))
+            .collect();
+
+        let outcomes = start_backups(&backups, stack, notifier, shutdown.clone()).await?;
+
+        for ((index, outcome), ping) in backup_indices.into_iter().zip(outcomes).zip(pings) {
+            let target = &mut targets[index];
+            let (result, summary) = match outcome.result {
+                Ok(summary) => (Ok(()), summary),
+                Err(err) => (Err(err), None),
+            };
+
+            if let Some(summary) = summary {
+                logPrint!("supervisor", "Backup of {}: {summary}", target.name());
+                state
+                    .state
+                    .backup_summaries
+                    .insert(target.name().to_string(), summary);
+            }
+
+            report(
+                notifier,
+                target.name(),
+                BackupJob::Backup,
+                outcome.took,
+                &result,
+            );
+            ping.finish(&result);
+
+            let advances_schedule = target.finish_backup(&result, Utc::now().with_timezone(&tz));
+            state.state.job(target.name(), BackupJob::Backup).record(
+                &result,
+                advances_schedule,
+                outcome.took,
+            );
+        }
+
+        state.save();
+    }
+
+    for (index, job) in due {
+        let target = &targets[*index];
+        let config = &target.config;
+        let started = Instant::now();
+
+        if *job == BackupJob::Backup {
+            continue;
+        }
+
+        let ping = pinger.start(target.ping_url(*job));
+        let result = match job {
+            BackupJob::Backup => unreachable!("backups are run together above"),
+            BackupJob::Forget => {
+                start_forget(config, config.retention.as_ref().unwrap(), shutdown.clone()).await
+            }
+            BackupJob::Prune => start_prune(config, shutdown.clone()).await,
+            BackupJob::Check => {
+                start_check(config, config.check.as_ref().unwrap(), shutdown.clone()).await
+            }
+            BackupJob::RestoreTest => {
+                let test = config.restore_test.as_ref().unwrap();
+                restore_test::run(config, test, shutdown.clone()).await
+            }
+        };
+
+        report(notifier, target.name(), *job, started.elapsed(), &result);
+        ping.finish(&result);
+        state
+            .state
+            .job(target.name(), *job)
+            .record(&result, true, started.elapsed());
+        targets[*index].schedule_from(*job, Utc::now().with_timezone(&tz));
+    }
+
+    state.save();
+    Ok(())
+}
+
+fn report(
+    notifier: &Notifier,
+    target: &str,
+    job: BackupJob,
+    took: Duration,
+    result: &anyhow::Result<()>,
+) {
+    match result {
+        Ok(()) => {
+            logPrint!("supervisor", "{job} of {target} succeeded");
+        }
+        Err(err) => {
+            elogPrint!("supervisor", "{job} of {target} FAILED: {err:?}");
+        }
+    }
+
+    notifier.notify(Notification::job(job, target, took, result));
+}
+
+async fn start_forget(
+    backup: &BackupConfig,
+    retention: &RetentionConfig,
+    shutdown: Shutdown,
+) -> anyhow::Result<()> {
+    // Without a separate prune schedule, prune straight away
+    let prune = retention.prune_interval.is_none();
+
+    run_to_end(
+        &format!("{}:forget", backup.name()),
+        retention::forget(backup, retention, prune),
+        shutdown,
+    )
+    .await
+}
+
+async fn start_prune(backup: &BackupConfig, shutdown: Shutdown) -> anyhow::Result<()> {
+    run_to_end(
+        &format!("{}:prune", backup.name()),
+        retention::prune(backup),
+        shutdown,
+    )
+    .await
+}
+
+async fn start_check(
+    backup: &BackupConfig,
+    check: &CheckConfig,
+    shutdown: Shutdown,
+) -> anyhow::Result<()> {
+    let result = run_to_end(
+        &format!("{}:check", backup.name()),
+        check::check(backup, check),
+        shutdown,
+    )
+    .await;
+
+    match result {
+        Ok(()) => {
+            logPrint!("supervisor", "Repository {} is healthy", backup.repo);
+            Ok(())
+        }
+        // Loud on purpose, a broken repository is easy to miss until a restore is needed
+        Err(err) => Err(err.context(format!(
+            "REPOSITORY CHECK FAILED for {}, backups may not be restorable",
+            backup.repo
+        ))),
+    }
+}
+
+/// Run hooks one after another. Hooks in an app's container wait for it to be ready first,
+/// it may only just have been started again after a backup.
+async fn run_hooks(
+    hooks: Option<&Vec<HookConfig>>,
+    stack: &mut Stack,
+    shutdown: &Shutdown,
+) -> anyhow::Result<()> {
+    for hook in hooks.into_iter().flatten() {
+        let container_name = match &hook.app {
+            Some(app) => {
+                stack.wait_ready(app).await?;
+                Some(
+                    stack
+                        .container_name(app)
+                        .with_context(|| format!("Unknown app {app}"))?,
+                )
+            }
+            None => None,
+        };
+
+        let mut process =
+            Process::new("hook", backup::hook(hook, container_name), shutdown.clone())
+                .with_context(|| format!("Starting hook {:?}", hook.command))?;
+
+        let status = process.wait().await.context("Waiting for hook process")?;
+        if !status.success() {
+            bail!("Hook {:?} exited with {status}", hook.command);
+        }
+    }
+
+    Ok(())
+}
@@ -1,5 +1,79 @@
+use std::{fs, path::Path};
+
+use anyhow::{bail, Context};
 use chrono_tz::Tz;
 
-pub fn current_timezone() -> Tz {
-    "Australia/Melbourne".parse().unwrap_or_else(|_e| Tz::UTC)
+use crate::log::{elogPrint, logPrint};
+
+/// Resolve the timezone used for scheduling. The configured name wins, then the `TZ`
+/// environment variable, then the zone `/etc/localtime` points to, and finally UTC.
+pub fn current_timezone(configured: Option<&str>) -> anyhow::Result<Tz> {
+    if let Some(name) = configured {
+        return parse_timezone(name).context("Invalid timezone in config");
+    }
+
+    if let Ok(value) = std::env::var("TZ") {
+        if let Some(tz) = timezone_from_env(&value) {
+            logPrint!("supervisor", "Using timezone {tz} from TZ");
+            return Ok(tz);
+        }
+    }
+
+    if let Some(tz) = system_timezone() {
+        logPrint!("supervisor", "Using system timezone {tz}");
+        return Ok(tz);
+    }
+
+    logPrint!(
+        "supervisor",
+        "Unable to determine local timezone, using UTC"
+    );
+    Ok(Tz::UTC)
+}
+
+fn parse_timezone(name: &str) -> anyhow::Result<Tz> {
+    match name.trim().parse() {
+        Ok(tz) => Ok(tz),
+        Err(_) => bail!("Unknown timezone {name:?}, expecting an IANA name like Europe/Berlin"),
+    }
+}
+
+/// `TZ` can also hold a POSIX rule like `UTC0` or `AEST-10AEDT`, which can't be mapped to a
+/// zone. Those are skipped with a warning rather than failing.
+fn timezone_from_env(value: &str) -> Option<Tz> {
+    let value = value.trim().trim_start_matches(':');
+    if value.is_empty() {
+        return None;
+    }
+
+    // `TZ=:/etc/localtime` style values point at a zoneinfo file rather than naming a zone
+    if value.starts_with('/') {
+        return zone_name_from_path(Path::new(value)).and_then(|n| n.parse().ok());
+    }
+
+    match parse_timezone(value) {
+        Ok(tz) => Some(tz),
+        Err(err) => {
+            elogPrint!("supervisor", "Ignoring TZ environment variable: {err}");
+            None
+        }
+    }
+}
+
+fn system_timezone() -> Option<Tz> {
+    if let Ok(target) = fs::read_link("/etc/localtime") {
+        if let Some(tz) = zone_name_from_path(&target).and_then(|n| n.parse().ok()) {
+            return Some(tz);
+        }
+    }
+
+    fs::read_to_string("/etc/timezone")
+        .ok()
+        .and_then(|name| name.trim().parse().ok())
+}
+
+fn zone_name_from_path(path: &Path) -> Option<String> {
+    let path = path.to_str()?;
+    let (_, name) = path.split_once("zoneinfo/")?;
+    Some(name.trim_start_matches("posix/").to_string())
 }